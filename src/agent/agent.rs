@@ -5,7 +5,7 @@ use tokio::{
     net::{UnixListener, UnixStream},
 };
 
-use super::{handler::SSHAgentHandler, protocol};
+use super::{handler::SSHAgentHandler, protocol, session::ConnectionState};
 
 pub struct Agent {
     pub listener: UnixListener,
@@ -20,6 +20,8 @@ async fn handle_connection(
     let mut read = BufReader::new(read);
     let mut write = BufWriter::new(write);
 
+    let mut session = ConnectionState::default();
+
     loop {
         let size = read.read_u32().await?;
         let mut buf = vec![0; size as usize];
@@ -29,7 +31,7 @@ async fn handle_connection(
 
         let request = protocol::Request::read(&mut cursor)?;
 
-        let response = handler.handle_request(request).await?;
+        let response = handler.handle_request(request, &mut session).await?;
 
         response.write(&mut write).await?;
         write.flush().await?;
@@ -60,3 +62,82 @@ impl Agent {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::private::KeypairData;
+
+    use super::*;
+    use crate::agent::protocol::{KeyConstraint, Response};
+
+    struct EmptyHandler;
+
+    #[async_trait::async_trait]
+    impl SSHAgentHandler for EmptyHandler {
+        async fn identities(&self, _: &ConnectionState) -> color_eyre::Result<Response> {
+            Ok(Response::Identities(vec![]))
+        }
+
+        async fn sign_request(
+            &self,
+            _: Vec<u8>,
+            _: Vec<u8>,
+            _: u32,
+            _: &ConnectionState,
+        ) -> color_eyre::Result<Response> {
+            Ok(Response::Failure)
+        }
+
+        async fn add_identity(
+            &self,
+            _: KeypairData,
+            _: String,
+            _: Vec<KeyConstraint>,
+        ) -> color_eyre::Result<Response> {
+            Ok(Response::Success)
+        }
+
+        async fn remove_identity(&self, _: Vec<u8>) -> color_eyre::Result<Response> {
+            Ok(Response::Failure)
+        }
+
+        async fn remove_all_identities(&self) -> color_eyre::Result<Response> {
+            Ok(Response::Failure)
+        }
+
+        async fn lock(&self, _: Vec<u8>) -> color_eyre::Result<Response> {
+            Ok(Response::Failure)
+        }
+
+        async fn unlock(&self, _: Vec<u8>) -> color_eyre::Result<Response> {
+            Ok(Response::Failure)
+        }
+    }
+
+    async fn request(stream: &mut UnixStream, message: &[u8]) -> Vec<u8> {
+        stream.write_u32(message.len() as u32).await.unwrap();
+        stream.write_all(message).await.unwrap();
+
+        let mut response = vec![0; stream.read_u32().await.unwrap() as usize];
+        stream.read_exact(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn unsupported_key_is_refused() {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(handle_connection(server, Arc::new(EmptyHandler)));
+
+        // SSH_AGENTC_ADD_IDENTITY with a certificate, which can't be decoded as a private key
+        let key_type = b"ssh-ed25519-cert-v01@openssh.com";
+        let mut message = vec![17];
+        message.extend((key_type.len() as u32).to_be_bytes());
+        message.extend(key_type);
+        message.extend([0, 0, 0, 4, 1, 2, 3, 4]);
+
+        assert_eq!(request(&mut client, &message).await, [5]);
+
+        // SSH_AGENTC_REQUEST_IDENTITIES still works
+        assert_eq!(request(&mut client, &[11]).await, [12, 0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,16 @@
+use super::{protocol::Response, session::ConnectionState};
+
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#name-query-extension
+pub const QUERY_EXTENSION: &str = "query";
+
+#[async_trait::async_trait]
+pub trait AgentExtension: Send + Sync {
+    // the extension name, e.g. `session-bind@openssh.com`
+    fn name(&self) -> &str;
+
+    async fn handle(
+        &self,
+        data: Vec<u8>,
+        session: &mut ConnectionState,
+    ) -> color_eyre::Result<Response>;
+}
@@ -1,28 +1,93 @@
-use super::protocol::{Request, Response};
+use ssh_key::private::KeypairData;
+
+use super::{
+    extension::{AgentExtension, QUERY_EXTENSION},
+    protocol::{KeyConstraint, Request, Response},
+    session::{ConnectionState, SessionBindExtension},
+};
+
+// extensions that are implemented by the agent itself
+static BUILTIN_EXTENSIONS: &[&dyn AgentExtension] = &[&SessionBindExtension];
 
 #[async_trait::async_trait]
 pub trait SSHAgentHandler: Send + Sync {
-    async fn identities(&self) -> color_eyre::Result<Response>;
+    async fn identities(&self, session: &ConnectionState) -> color_eyre::Result<Response>;
     async fn sign_request(
         &self,
         pubkey: Vec<u8>,
         data: Vec<u8>,
         flags: u32,
+        session: &ConnectionState,
     ) -> color_eyre::Result<Response>;
+    async fn add_identity(
+        &self,
+        key: KeypairData,
+        comment: String,
+        constraints: Vec<KeyConstraint>,
+    ) -> color_eyre::Result<Response>;
+    async fn remove_identity(&self, pubkey: Vec<u8>) -> color_eyre::Result<Response>;
+    async fn remove_all_identities(&self) -> color_eyre::Result<Response>;
+    async fn lock(&self, passphrase: Vec<u8>) -> color_eyre::Result<Response>;
+    async fn unlock(&self, passphrase: Vec<u8>) -> color_eyre::Result<Response>;
+
+    // additional extensions supported by the handler
+    fn extensions(&self) -> &[Box<dyn AgentExtension>] {
+        &[]
+    }
+
+    async fn handle_extension(
+        &self,
+        name: String,
+        data: Vec<u8>,
+        session: &mut ConnectionState,
+    ) -> color_eyre::Result<Response> {
+        let extensions = BUILTIN_EXTENSIONS
+            .iter()
+            .copied()
+            .chain(self.extensions().iter().map(|e| e.as_ref()));
 
-    async fn handle_request(&self, request: Request) -> color_eyre::Result<Response> {
+        if name == QUERY_EXTENSION {
+            let mut names = vec![String::from(QUERY_EXTENSION)];
+            names.extend(extensions.map(|e| e.name().to_string()));
+
+            return Ok(Response::ExtensionNames(names));
+        }
+
+        for extension in extensions {
+            if extension.name() == name {
+                return extension.handle(data, session).await;
+            }
+        }
+
+        Ok(Response::ExtensionFailure)
+    }
+
+    async fn handle_request(
+        &self,
+        request: Request,
+        session: &mut ConnectionState,
+    ) -> color_eyre::Result<Response> {
         match request {
-            Request::RequestIdentities => self.identities().await,
+            Request::RequestIdentities => self.identities(session).await,
             Request::SignRequest {
                 ref pubkey_blob,
                 ref data,
                 ref flags,
             } => {
-                self.sign_request(pubkey_blob.clone(), data.clone(), *flags)
+                self.sign_request(pubkey_blob.clone(), data.clone(), *flags, session)
                     .await
             }
+            Request::AddIdentity {
+                key,
+                comment,
+                constraints,
+            } => self.add_identity(key, comment, constraints).await,
+            Request::RemoveIdentity { pubkey_blob } => self.remove_identity(pubkey_blob).await,
+            Request::RemoveAllIdentities => self.remove_all_identities().await,
+            Request::Lock { passphrase } => self.lock(passphrase).await,
+            Request::Unlock { passphrase } => self.unlock(passphrase).await,
             Request::Unknown => Ok(Response::Failure),
-            Request::Extension { .. } => Ok(Response::Failure),
+            Request::Extension { name, data } => self.handle_extension(name, data, session).await,
         }
     }
 }
@@ -1,3 +1,5 @@
 pub mod agent;
+pub mod extension;
 pub mod handler;
 pub mod protocol;
+pub mod session;
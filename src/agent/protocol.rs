@@ -1,7 +1,10 @@
 use bitflags::bitflags;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use ssh_encoding::Decode;
+use ssh_key::private::KeypairData;
 use std::io::{Read, Write};
 use tokio::io::AsyncWrite;
+use zeroize::Zeroizing;
 
 #[derive(Debug, Copy, Clone)]
 enum MessageRequest {
@@ -49,6 +52,39 @@ fn read_message(stream: &mut dyn Read) -> color_eyre::Result<Vec<u8>> {
     Ok(buf)
 }
 
+fn read_private_key(
+    stream: &mut dyn Read,
+    constrained: bool,
+) -> color_eyre::Result<(KeypairData, String, Vec<KeyConstraint>)> {
+    // private keys are not length-prefixed, so we need to consume the rest of the message
+    let mut buf = Zeroizing::new(Vec::new());
+    stream.read_to_end(&mut buf)?;
+
+    let mut reader = buf.as_slice();
+    let key = KeypairData::decode(&mut reader)?;
+    let comment = String::decode(&mut reader)?;
+
+    let mut constraints = Vec::new();
+    if constrained {
+        while !reader.is_empty() {
+            let constraint = KeyConstraint::read(&mut reader)?;
+            let is_unsupported = matches!(
+                constraint,
+                KeyConstraint::Extension { .. } | KeyConstraint::Unknown(_)
+            );
+
+            constraints.push(constraint);
+
+            // we don't know the format of the remaining data, so we can't parse any further
+            if is_unsupported {
+                break;
+            }
+        }
+    }
+
+    Ok((key, comment, constraints))
+}
+
 fn write_message(w: &mut dyn Write, string: &[u8]) -> color_eyre::Result<()> {
     w.write_u32::<BigEndian>(string.len() as u32)?;
     w.write_all(string)?;
@@ -65,6 +101,31 @@ bitflags! {
     }
 }
 
+// https://datatracker.ietf.org/doc/html/draft-miller-ssh-agent#name-key-constraints
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyConstraint {
+    // Number of seconds after which the key should be removed.
+    Lifetime(u32),
+    // Every use of the key should be explicitly confirmed by the user.
+    Confirm,
+    // A vendor-specific constraint.
+    Extension { name: String },
+    Unknown(u8),
+}
+
+impl KeyConstraint {
+    fn read(mut buf: &mut dyn Read) -> color_eyre::Result<Self> {
+        match buf.read_u8()? {
+            1 => Ok(KeyConstraint::Lifetime(buf.read_u32::<BigEndian>()?)),
+            2 => Ok(KeyConstraint::Confirm),
+            255 => Ok(KeyConstraint::Extension {
+                name: String::from_utf8(read_message(&mut buf)?)?,
+            }),
+            other => Ok(KeyConstraint::Unknown(other)),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Request {
     RequestIdentities,
@@ -77,6 +138,28 @@ pub enum Request {
         // Request flags.
         flags: u32,
     },
+    AddIdentity {
+        // The private key (encoded as per the OpenSSH private key format).
+        key: KeypairData,
+        // The comment of the key.
+        comment: String,
+        // Constraints on the key usage (only for SSH_AGENTC_ADD_ID_CONSTRAINED).
+        constraints: Vec<KeyConstraint>,
+    },
+    RemoveIdentity {
+        // Blob of the public key
+        // (encoded as per RFC4253 "6.6. Public Key Algorithms").
+        pubkey_blob: Vec<u8>,
+    },
+    RemoveAllIdentities,
+    Lock {
+        // The passphrase to lock the agent with.
+        passphrase: Vec<u8>,
+    },
+    Unlock {
+        // The passphrase the agent was locked with.
+        passphrase: Vec<u8>,
+    },
     Extension {
         // The name of the extension.
         name: String,
@@ -89,21 +172,44 @@ pub enum Request {
 impl Request {
     pub fn read(mut buf: &mut dyn Read) -> color_eyre::Result<Self> {
         let msg = buf.read_u8()?;
-        match MessageRequest::from_u8(msg) {
+        let msg_type = MessageRequest::from_u8(msg);
+        match msg_type {
             MessageRequest::RequestIdentities => Ok(Request::RequestIdentities),
             MessageRequest::SignRequest => Ok(Request::SignRequest {
                 pubkey_blob: read_message(&mut buf)?,
                 data: read_message(&mut buf)?,
                 flags: buf.read_u32::<BigEndian>()?,
             }),
-            MessageRequest::AddIdentity => Ok(Request::Unknown),
-            MessageRequest::RemoveIdentity => Ok(Request::Unknown),
-            MessageRequest::RemoveAllIdentities => Ok(Request::Unknown),
-            MessageRequest::AddIdConstrained => Ok(Request::Unknown),
+            MessageRequest::AddIdentity | MessageRequest::AddIdConstrained => {
+                let constrained = matches!(msg_type, MessageRequest::AddIdConstrained);
+                // e.g. an unsupported key type, or a key with a certificate.
+                // the message has been consumed either way, so the connection can go on
+                let (key, comment, constraints) = match read_private_key(&mut buf, constrained) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        eprintln!("Error reading the key to add: {e}");
+                        return Ok(Request::Unknown);
+                    }
+                };
+
+                Ok(Request::AddIdentity {
+                    key,
+                    comment,
+                    constraints,
+                })
+            }
+            MessageRequest::RemoveIdentity => Ok(Request::RemoveIdentity {
+                pubkey_blob: read_message(&mut buf)?,
+            }),
+            MessageRequest::RemoveAllIdentities => Ok(Request::RemoveAllIdentities),
             MessageRequest::AddSmartcardKey => Ok(Request::Unknown),
             MessageRequest::RemoveSmartcardKey => Ok(Request::Unknown),
-            MessageRequest::Lock => Ok(Request::Unknown),
-            MessageRequest::Unlock => Ok(Request::Unknown),
+            MessageRequest::Lock => Ok(Request::Lock {
+                passphrase: read_message(&mut buf)?,
+            }),
+            MessageRequest::Unlock => Ok(Request::Unlock {
+                passphrase: read_message(&mut buf)?,
+            }),
             MessageRequest::AddSmartcardKeyConstrained => Ok(Request::Unknown),
             MessageRequest::Extension => Ok(Request::Extension {
                 name: String::from_utf8(read_message(&mut buf)?)?,
@@ -119,6 +225,7 @@ enum MessageResponse {
     AgentSuccess = 6,
     AgentIdentitiesAnswer = 12,
     AgentSignResponse = 14,
+    AgentExtensionFailure = 28,
 }
 
 #[derive(Debug)]
@@ -136,6 +243,9 @@ pub enum Response {
         algo_name: String,
         signature: Vec<u8>,
     },
+    // Response to the `query` extension.
+    ExtensionNames(Vec<String>),
+    ExtensionFailure,
 }
 
 impl Response {
@@ -147,6 +257,16 @@ impl Response {
         match *self {
             Response::Success => buf.write_u8(MessageResponse::AgentSuccess as u8)?,
             Response::Failure => buf.write_u8(MessageResponse::AgentFailure as u8)?,
+            Response::ExtensionNames(ref names) => {
+                buf.write_u8(MessageResponse::AgentSuccess as u8)?;
+
+                for name in names {
+                    write_message(&mut buf, name.as_bytes())?;
+                }
+            }
+            Response::ExtensionFailure => {
+                buf.write_u8(MessageResponse::AgentExtensionFailure as u8)?
+            }
             Response::Identities(ref identities) => {
                 buf.write_u8(MessageResponse::AgentIdentitiesAnswer as u8)?;
                 buf.write_u32::<BigEndian>(identities.len() as u32)?;
@@ -0,0 +1,318 @@
+use color_eyre::eyre::eyre;
+use signature::Verifier;
+use ssh_encoding::Decode;
+use ssh_key::{HashAlg, PublicKey, Signature};
+
+use super::{extension::AgentExtension, protocol::Response};
+
+pub const SESSION_BIND_EXTENSION: &str = "session-bind@openssh.com";
+
+// same as openssh
+const MAX_SESSION_BINDINGS: usize = 16;
+
+const SSH_MSG_USERAUTH_REQUEST: u8 = 50;
+const PUBLICKEY_METHOD: &str = "publickey";
+const PUBLICKEY_HOSTBOUND_METHOD: &str = "publickey-hostbound-v00@openssh.com";
+
+// https://raw.githubusercontent.com/openssh/openssh-portable/master/PROTOCOL.agent
+#[derive(Debug, Clone)]
+pub struct SessionBind {
+    // The host key of the server the session was established with.
+    pub host_key: PublicKey,
+    // The exchange hash of the initial key exchange.
+    pub session_id: Vec<u8>,
+    // Whether the agent is going to be forwarded over this session,
+    // as opposed to being used for user authentication.
+    pub is_forwarding: bool,
+}
+
+impl SessionBind {
+    pub fn read(mut data: &[u8]) -> color_eyre::Result<Self> {
+        let host_key = PublicKey::from_bytes(&Vec::<u8>::decode(&mut data)?)?;
+        let session_id = Vec::<u8>::decode(&mut data)?;
+        let signature = Signature::try_from(Vec::<u8>::decode(&mut data)?.as_slice())?;
+        let is_forwarding = u8::decode(&mut data)? != 0;
+
+        // the server proves that it owns the host key by signing the session id
+        host_key
+            .key_data()
+            .verify(&session_id, &signature)
+            .map_err(|e| eyre!("Invalid host key signature: {e}"))?;
+
+        Ok(Self {
+            host_key,
+            session_id,
+            is_forwarding,
+        })
+    }
+
+    pub fn host_key_fingerprint(&self) -> String {
+        self.host_key.fingerprint(HashAlg::Sha256).to_string()
+    }
+}
+
+// the data signed for public key user authentication, see RFC 4252 section 7
+// and PROTOCOL in openssh for the hostbound variant
+#[derive(Debug)]
+pub struct UserAuthRequest {
+    pub session_id: Vec<u8>,
+    pub user: String,
+    // blob of the key (or certificate) used for authentication
+    pub public_key: Vec<u8>,
+    // the server host key, only for publickey-hostbound-v00@openssh.com
+    pub host_key: Option<PublicKey>,
+}
+
+impl UserAuthRequest {
+    pub fn read(mut data: &[u8]) -> color_eyre::Result<Self> {
+        let session_id = Vec::<u8>::decode(&mut data)?;
+
+        if u8::decode(&mut data)? != SSH_MSG_USERAUTH_REQUEST {
+            return Err(eyre!("Not a user authentication request"));
+        }
+
+        let user = String::decode(&mut data)?;
+        let service = String::decode(&mut data)?;
+        if service != "ssh-connection" {
+            return Err(eyre!("Unexpected service {service}"));
+        }
+
+        let method = String::decode(&mut data)?;
+        if u8::decode(&mut data)? == 0 {
+            return Err(eyre!("User authentication request without a signature"));
+        }
+
+        let _algorithm = String::decode(&mut data)?;
+        let public_key = Vec::<u8>::decode(&mut data)?;
+
+        let host_key = match method.as_str() {
+            PUBLICKEY_METHOD => None,
+            PUBLICKEY_HOSTBOUND_METHOD => {
+                Some(PublicKey::from_bytes(&Vec::<u8>::decode(&mut data)?)?)
+            }
+            _ => return Err(eyre!("Unexpected authentication method {method}")),
+        };
+
+        if !data.is_empty() {
+            return Err(eyre!(
+                "Unexpected data after the user authentication request"
+            ));
+        }
+
+        Ok(Self {
+            session_id,
+            user,
+            public_key,
+            host_key,
+        })
+    }
+}
+
+// state of a single agent connection
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    // bindings in the order they were received, one per hop
+    pub bindings: Vec<SessionBind>,
+}
+
+impl ConnectionState {
+    pub fn bind(&mut self, bind: SessionBind) -> color_eyre::Result<()> {
+        for existing in &self.bindings {
+            if !existing.is_forwarding {
+                return Err(eyre!(
+                    "Connection was previously bound for authentication attempt"
+                ));
+            }
+
+            if existing.session_id == bind.session_id {
+                if existing.host_key.key_data() == bind.host_key.key_data() {
+                    // already recorded
+                    return Ok(());
+                }
+
+                return Err(eyre!("Session ID recorded against a different host key"));
+            }
+        }
+
+        if self.bindings.len() >= MAX_SESSION_BINDINGS {
+            return Err(eyre!("Too many session bindings"));
+        }
+
+        self.bindings.push(bind);
+
+        Ok(())
+    }
+
+    // same as openssh for destination-constrained keys: the data has to be a user authentication
+    // request with the given key for the session of the last hop. returns the binding of that hop
+    pub fn verify_user_auth(
+        &self,
+        data: &[u8],
+        public_key: &[u8],
+    ) -> color_eyre::Result<&SessionBind> {
+        let Some(bind) = self.bindings.last() else {
+            return Err(eyre!("Connection is not bound to a session"));
+        };
+
+        if bind.is_forwarding {
+            return Err(eyre!(
+                "Connection is bound for forwarding, not authentication"
+            ));
+        }
+
+        let request = UserAuthRequest::read(data)?;
+
+        if request.public_key != public_key {
+            return Err(eyre!("Signed data is for a different key"));
+        }
+
+        if request.session_id != bind.session_id {
+            return Err(eyre!("Signed data is for a different session"));
+        }
+
+        if let Some(ref host_key) = request.host_key {
+            if host_key.key_data() != bind.host_key.key_data() {
+                return Err(eyre!("Signed data is for a different host key"));
+            }
+        }
+
+        Ok(bind)
+    }
+
+    // whether the connection came through a forwarded agent
+    pub fn is_forwarded(&self) -> bool {
+        self.bindings.iter().any(|b| b.is_forwarding)
+    }
+}
+
+pub struct SessionBindExtension;
+
+#[async_trait::async_trait]
+impl AgentExtension for SessionBindExtension {
+    fn name(&self) -> &str {
+        SESSION_BIND_EXTENSION
+    }
+
+    async fn handle(
+        &self,
+        data: Vec<u8>,
+        session: &mut ConnectionState,
+    ) -> color_eyre::Result<Response> {
+        let bind = match SessionBind::read(&data) {
+            Ok(bind) => bind,
+            Err(e) => {
+                eprintln!("Error parsing session binding: {:?}", e);
+                return Ok(Response::Failure);
+            }
+        };
+
+        match session.bind(bind) {
+            Ok(()) => Ok(Response::Success),
+            Err(e) => {
+                eprintln!("Error binding session: {:?}", e);
+                Ok(Response::Failure)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ssh_key::{rand_core::OsRng, Algorithm, PrivateKey};
+
+    use super::*;
+
+    const SESSION_ID: &[u8] = &[1; 32];
+
+    fn put_string(buf: &mut Vec<u8>, data: &[u8]) {
+        buf.extend((data.len() as u32).to_be_bytes());
+        buf.extend(data);
+    }
+
+    fn random_key() -> PublicKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+            .unwrap()
+            .public_key()
+            .clone()
+    }
+
+    fn user_auth_request(session_id: &[u8], key: &[u8], host_key: Option<&PublicKey>) -> Vec<u8> {
+        let mut data = Vec::new();
+        put_string(&mut data, session_id);
+        data.push(SSH_MSG_USERAUTH_REQUEST);
+        put_string(&mut data, b"git");
+        put_string(&mut data, b"ssh-connection");
+        match host_key {
+            Some(_) => put_string(&mut data, PUBLICKEY_HOSTBOUND_METHOD.as_bytes()),
+            None => put_string(&mut data, PUBLICKEY_METHOD.as_bytes()),
+        }
+        data.push(1);
+        put_string(&mut data, b"ssh-ed25519");
+        put_string(&mut data, key);
+        if let Some(host_key) = host_key {
+            put_string(&mut data, &host_key.to_bytes().unwrap());
+        }
+        data
+    }
+
+    fn bound_session(host_key: &PublicKey) -> ConnectionState {
+        ConnectionState {
+            bindings: vec![SessionBind {
+                host_key: host_key.clone(),
+                session_id: SESSION_ID.to_vec(),
+                is_forwarding: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn matching_request() {
+        let host_key = random_key();
+        let key = random_key().to_bytes().unwrap();
+        let session = bound_session(&host_key);
+
+        let data = user_auth_request(SESSION_ID, &key, None);
+        assert!(session.verify_user_auth(&data, &key).is_ok());
+
+        let data = user_auth_request(SESSION_ID, &key, Some(&host_key));
+        assert!(session.verify_user_auth(&data, &key).is_ok());
+    }
+
+    #[test]
+    fn forged_session_id() {
+        let host_key = random_key();
+        let key = random_key().to_bytes().unwrap();
+        let session = bound_session(&host_key);
+
+        let data = user_auth_request(&[2; 32], &key, Some(&host_key));
+        assert!(session.verify_user_auth(&data, &key).is_err());
+    }
+
+    #[test]
+    fn mismatched_hostbound_key() {
+        let key = random_key().to_bytes().unwrap();
+        let session = bound_session(&random_key());
+
+        let data = user_auth_request(SESSION_ID, &key, Some(&random_key()));
+        assert!(session.verify_user_auth(&data, &key).is_err());
+    }
+
+    #[test]
+    fn forwarding_hop() {
+        let host_key = random_key();
+        let key = random_key().to_bytes().unwrap();
+        let mut session = bound_session(&host_key);
+        session.bindings[0].is_forwarding = true;
+
+        let data = user_auth_request(SESSION_ID, &key, None);
+        assert!(session.verify_user_auth(&data, &key).is_err());
+    }
+
+    #[test]
+    fn arbitrary_data() {
+        let key = random_key().to_bytes().unwrap();
+        let session = bound_session(&random_key());
+
+        assert!(session.verify_user_auth(b"git commit", &key).is_err());
+    }
+}
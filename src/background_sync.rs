@@ -0,0 +1,357 @@
+use std::{sync::Arc, time::Duration};
+
+use ssh_key::rand_core::{OsRng, RngCore};
+use tokio::{sync::Mutex, time::Instant};
+
+use crate::{
+    bitwarden::{
+        auth::{identity::IdentityClient, token::TokenManager},
+        config::bw_get_config,
+        constants::get_bw_http_client,
+        notifications::{NotificationsClient, PushNotification, PushType},
+        sync::{bw_get_cipher, bw_get_revision_date, CipherDetailsResponseModel, CipherType},
+    },
+    ca::parse_duration,
+    cmd::{
+        config::{EXPOSE_ALL_SSH_KEYS_SETTING, SYNC_INTERVAL_SETTING},
+        sync::{delete_cipher, is_key_cipher, sync_keys, sync_single_cipher},
+    },
+    database::Database,
+    handler::Handler,
+};
+
+const DEFAULT_SYNC_INTERVAL_SECONDS: u64 = 15 * 60;
+// how often to check whether the sync was enabled again
+const DISABLED_RECHECK_SECONDS: u64 = 60;
+const RETRY_MIN_SECONDS: u64 = 30;
+const RETRY_MAX_SECONDS: u64 = 60 * 60;
+const NOTIFICATIONS_RETRY_MIN_SECONDS: u64 = 5;
+const NOTIFICATIONS_RETRY_MAX_SECONDS: u64 = 10 * 60;
+// a connection that lasted this long resets the backoff
+const NOTIFICATIONS_STABLE_SECONDS: u64 = 60;
+// well within the renewal margin of the issued certificates
+const CERTIFICATE_RENEWAL_CHECK_SECONDS: u64 = 5 * 60;
+
+// the periodic sync and the notifications both write the identities, don't let them interleave
+static SYNC_LOCK: Mutex<()> = Mutex::const_new(());
+
+enum SyncScope<'a> {
+    // only if the account revision date has changed since the last sync
+    IfChanged,
+    Full,
+    Cipher(&'a str),
+}
+
+// keeps the identities up to date while the daemon is running. the vault is only fetched
+// (and the keychain accessed) when the account revision date has changed since the last sync
+pub async fn run_background_sync(handler: Arc<Handler>) -> color_eyre::Result<()> {
+    // separate connection, so that agent requests aren't blocked while syncing
+    let database = Database::open()?;
+    let client = get_bw_http_client();
+    let mut failures = 0u32;
+
+    loop {
+        let Some(interval) = get_sync_interval(&database) else {
+            tokio::time::sleep(Duration::from_secs(DISABLED_RECHECK_SECONDS)).await;
+            continue;
+        };
+
+        match sync(&database, &client, &handler, SyncScope::IfChanged).await {
+            Ok(()) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                eprintln!("Background sync failed: {e}");
+            }
+        }
+
+        let delay = match failures {
+            0 => interval,
+            _ => backoff(failures, RETRY_MIN_SECONDS, RETRY_MAX_SECONDS),
+        };
+        tokio::time::sleep(with_jitter(delay)).await;
+    }
+}
+
+// applies the changes pushed by the server right away, so that e.g. a key deleted in the
+// web vault stops working within seconds. anything missed is picked up by the periodic sync
+pub async fn run_notifications(handler: Arc<Handler>) -> color_eyre::Result<()> {
+    let database = Database::open()?;
+    let client = get_bw_http_client();
+    let mut failures = 0u32;
+
+    loop {
+        let connected_at = Instant::now();
+        let result = match database.get_auth() {
+            Ok(Some(_)) => listen_for_notifications(&database, &client, &handler).await,
+            Ok(_) => {
+                tokio::time::sleep(Duration::from_secs(DISABLED_RECHECK_SECONDS)).await;
+                continue;
+            }
+            // e.g. the database is busy
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = result {
+            eprintln!("Notifications connection failed: {e}");
+
+            if connected_at.elapsed() >= Duration::from_secs(NOTIFICATIONS_STABLE_SECONDS) {
+                failures = 0;
+            }
+            failures += 1;
+
+            tokio::time::sleep(with_jitter(backoff(
+                failures,
+                NOTIFICATIONS_RETRY_MIN_SECONDS,
+                NOTIFICATIONS_RETRY_MAX_SECONDS,
+            )))
+            .await;
+        }
+    }
+}
+
+// certificates are issued here rather than when listing the keys, since that might need the
+// keychain. runs again after every change to the vault, so that e.g. a new key gets one right away
+pub async fn run_certificate_renewal(handler: Arc<Handler>) -> color_eyre::Result<()> {
+    loop {
+        if let Err(e) = handler.renew_certificates().await {
+            eprintln!("Error renewing certificates: {e}");
+        }
+
+        handler
+            .wait_for_vault_change(Duration::from_secs(CERTIFICATE_RENEWAL_CHECK_SECONDS))
+            .await;
+    }
+}
+
+fn get_sync_interval(database: &Database) -> Option<u64> {
+    let value = match database.get_setting(SYNC_INTERVAL_SETTING) {
+        Ok(Some(value)) => value,
+        Ok(None) => return Some(DEFAULT_SYNC_INTERVAL_SECONDS),
+        Err(e) => {
+            eprintln!("Error reading the sync interval: {e}");
+            return Some(DEFAULT_SYNC_INTERVAL_SECONDS);
+        }
+    };
+
+    if value == "off" {
+        return None;
+    }
+
+    match parse_duration(&value) {
+        Ok(0) => None,
+        Ok(seconds) => Some(seconds),
+        Err(e) => {
+            eprintln!("{e}, using the default sync interval");
+            Some(DEFAULT_SYNC_INTERVAL_SECONDS)
+        }
+    }
+}
+
+fn backoff(failures: u32, min: u64, max: u64) -> u64 {
+    min.saturating_mul(1 << (failures - 1).min(16)).min(max)
+}
+
+// +-10%, so that the daemons don't all hit the server at the same time
+fn with_jitter(seconds: u64) -> Duration {
+    let percent = 90 + OsRng.next_u64() % 21;
+    Duration::from_millis(seconds * percent * 10)
+}
+
+async fn sync(
+    database: &Database,
+    client: &reqwest::Client,
+    handler: &Handler,
+    scope: SyncScope<'_>,
+) -> color_eyre::Result<()> {
+    let _lock = SYNC_LOCK.lock().await;
+
+    let Some(auth) = database.get_auth()? else {
+        return Ok(());
+    };
+
+    let config = bw_get_config(client, &auth.vault_url).await?;
+    let identity = IdentityClient::new(
+        client,
+        &config.environment.identity,
+        &config.environment.api,
+        &auth.email,
+    );
+    let mut token_manager = TokenManager::new(database, &identity, &auth);
+
+    // everything up to here has to be done without the keychain, which might prompt the user
+    let cipher = match scope {
+        SyncScope::IfChanged => {
+            let access_token = token_manager.get_access_token().await?;
+            let revision_date = bw_get_revision_date(client, &config, access_token).await?;
+            if auth.revision_date == Some(revision_date) {
+                return Ok(());
+            }
+
+            println!("Vault has changed, syncing");
+            None
+        }
+        SyncScope::Full => None,
+        SyncScope::Cipher(cipher_id) => {
+            let access_token = token_manager.get_access_token().await?;
+            // gone if it was deleted or we lost access to it
+            let cipher = bw_get_cipher(client, &config, access_token, cipher_id)
+                .await?
+                .filter(is_key_cipher);
+
+            let is_known = database.get_identities()?.iter().any(|i| i.id == cipher_id)
+                || database
+                    .get_certificate_authorities()?
+                    .iter()
+                    .any(|c| c.id == cipher_id);
+
+            match cipher {
+                Some(cipher)
+                    if is_known
+                        || could_be_key(
+                            &cipher,
+                            database.get_bool_setting(EXPOSE_ALL_SSH_KEYS_SETTING)?,
+                        ) =>
+                {
+                    Some(cipher)
+                }
+                Some(_) => return Ok(()),
+                None => {
+                    if delete_cipher(database, cipher_id)? {
+                        handler.vault_changed().await;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    let symmetric_key = handler.decrypt_data(auth.symmetric_key.to_vec()).await?;
+    let private_key = match auth.private_key {
+        Some(ref private_key) => Some(handler.decrypt_data(private_key.to_vec()).await?),
+        None => None,
+    };
+    let private_key = private_key.as_deref().map(Vec::as_slice);
+
+    match cipher {
+        Some(ref cipher) => {
+            sync_single_cipher(
+                database,
+                client,
+                &config,
+                &symmetric_key,
+                private_key,
+                &mut token_manager,
+                cipher,
+            )
+            .await?
+        }
+        None => {
+            sync_keys(
+                database,
+                client,
+                &config,
+                &symmetric_key,
+                private_key,
+                &mut token_manager,
+            )
+            .await?
+        }
+    }
+
+    handler.vault_changed().await;
+
+    Ok(())
+}
+
+// whether a new item might hold a key, judging only by what can be seen without decrypting it.
+// apart from ssh key items with expose-all-ssh-keys, keys are only used if one of our fields is set
+fn could_be_key(cipher: &CipherDetailsResponseModel, expose_all_ssh_keys: bool) -> bool {
+    let has_fields = cipher
+        .fields
+        .as_ref()
+        .is_some_and(|fields| !fields.is_empty());
+
+    match cipher.type_field {
+        CipherType::SshKey => expose_all_ssh_keys || has_fields,
+        _ => cipher.notes.is_some() && has_fields,
+    }
+}
+
+// returns once logged out, or with an error when the connection is lost
+async fn listen_for_notifications(
+    database: &Database,
+    client: &reqwest::Client,
+    handler: &Handler,
+) -> color_eyre::Result<()> {
+    let mut connection = {
+        let Some(auth) = database.get_auth()? else {
+            return Ok(());
+        };
+
+        let config = bw_get_config(client, &auth.vault_url).await?;
+        let identity = IdentityClient::new(
+            client,
+            &config.environment.identity,
+            &config.environment.api,
+            &auth.email,
+        );
+        let mut token_manager = TokenManager::new(database, &identity, &auth);
+        let access_token = token_manager.get_access_token().await?;
+
+        NotificationsClient::connect(&config, access_token).await?
+    };
+
+    // we might have missed something while we weren't connected
+    if let Err(e) = sync(database, client, handler, SyncScope::IfChanged).await {
+        eprintln!("Background sync failed: {e}");
+    }
+
+    loop {
+        let notification = connection.next().await?;
+
+        if let Err(e) = handle_notification(database, client, handler, &notification).await {
+            eprintln!(
+                "Error handling the {:?} notification: {e}",
+                notification.push_type
+            );
+        }
+
+        if let Ok(None) = database.get_auth() {
+            return Ok(());
+        }
+    }
+}
+
+async fn handle_notification(
+    database: &Database,
+    client: &reqwest::Client,
+    handler: &Handler,
+    notification: &PushNotification,
+) -> color_eyre::Result<()> {
+    match (notification.push_type, &notification.id) {
+        // no need to ask the server (or the keychain) about deleted items
+        (PushType::SyncCipherDelete | PushType::SyncLoginDelete, Some(cipher_id)) => {
+            let _lock = SYNC_LOCK.lock().await;
+            if delete_cipher(database, cipher_id)? {
+                handler.vault_changed().await;
+            }
+        }
+        (PushType::SyncCipherCreate | PushType::SyncCipherUpdate, Some(cipher_id)) => {
+            sync(database, client, handler, SyncScope::Cipher(cipher_id)).await?;
+        }
+        (PushType::SyncCiphers | PushType::SyncVault | PushType::SyncOrgKeys, _) => {
+            sync(database, client, handler, SyncScope::Full).await?;
+        }
+        // e.g. the password was changed or all sessions were deauthorized
+        (PushType::LogOut, _) => {
+            let _lock = SYNC_LOCK.lock().await;
+            database.logout()?;
+            handler.vault_changed().await;
+
+            println!("Logged out by the server. Please log in again using `bw-ssh-agent login`");
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
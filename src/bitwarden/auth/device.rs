@@ -0,0 +1,38 @@
+use crate::{cmd::config::DEVICE_NAME_SETTING, database::Database};
+
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub identifier: String,
+    pub name: String,
+}
+
+impl Device {
+    // the identifier is generated on first use and reused afterwards, otherwise every login
+    // shows up as a new device (with new device emails and verification)
+    pub fn load(database: &Database) -> color_eyre::Result<Self> {
+        let identifier = match database.get_device_identifier()? {
+            Some(identifier) => identifier,
+            None => {
+                let identifier = uuid::Uuid::new_v4().to_string();
+                database.set_device_identifier(&identifier)?;
+                identifier
+            }
+        };
+
+        let name = match database.get_setting(DEVICE_NAME_SETTING)? {
+            Some(name) => name,
+            None => Self::default_name(),
+        };
+
+        Ok(Self { identifier, name })
+    }
+
+    fn default_name() -> String {
+        let host = sysinfo::System::host_name().unwrap_or_else(|| String::from("unknown"));
+
+        match sysinfo::System::long_os_version() {
+            Some(os) => format!("bw-ssh-agent on {host} ({os})"),
+            None => format!("bw-ssh-agent on {host}"),
+        }
+    }
+}
@@ -1,23 +1,42 @@
+use std::time::Duration;
+
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use color_eyre::eyre::eyre;
 use serde::de::DeserializeOwned;
 
-use super::identity_dto::{
-    IdentityTokenRefreshResponse, IdentityTokenSuccessResponse, PasswordTokenRequest,
-    RenewTokenRequest,
+use super::{
+    device::Device,
+    identity_dto::{
+        AuthRequestCreateRequest, AuthRequestResponse, ClientCredentialsTokenRequest,
+        IdentityTokenErrorResponse, IdentityTokenRefreshResponse, IdentityTokenSuccessResponse,
+        PasswordLoginResponse, PasswordTokenRequest, RenewTokenRequest, DEVICE_TYPE,
+    },
+    two_factor::TwoFactorRequest,
 };
 
 pub struct IdentityClient<'a> {
     client: &'a reqwest::Client,
     base: &'a str,
+    // auth requests live in the api rather than the identity server
+    api_base: &'a str,
     email: &'a str,
 }
 
 impl<'a> IdentityClient<'a> {
-    pub fn new(client: &'a reqwest::Client, base: &'a str, email: &'a str) -> Self {
+    // other processes wait for the token renewal, and login keeps polling the auth request,
+    // so a request shouldn't hang forever
+    const REQUEST_TIMEOUT_SECONDS: u64 = 30;
+
+    pub fn new(
+        client: &'a reqwest::Client,
+        base: &'a str,
+        api_base: &'a str,
+        email: &'a str,
+    ) -> Self {
         Self {
             client,
             base,
+            api_base,
             email,
         }
     }
@@ -26,8 +45,112 @@ impl<'a> IdentityClient<'a> {
         &self,
         email: &str,
         password: &str,
+        device: &Device,
+        two_factor: Option<&TwoFactorRequest>,
+        new_device_otp: Option<&str>,
+    ) -> color_eyre::Result<PasswordLoginResponse> {
+        let req = PasswordTokenRequest::new(email, password, device, two_factor)
+            .with_new_device_otp(new_device_otp);
+        self.password_grant(req).await
+    }
+
+    pub async fn auth_request_login(
+        &self,
+        email: &str,
+        access_code: &str,
+        auth_request_id: &str,
+        device: &Device,
+    ) -> color_eyre::Result<PasswordLoginResponse> {
+        let req = PasswordTokenRequest::new(email, access_code, device, None)
+            .with_auth_request(auth_request_id);
+        self.password_grant(req).await
+    }
+
+    pub async fn create_auth_request(
+        &self,
+        public_key: &str,
+        device_identifier: &str,
+        access_code: &str,
+    ) -> color_eyre::Result<AuthRequestResponse> {
+        let url = format!("{}/auth-requests", self.api_base);
+        let req = AuthRequestCreateRequest {
+            email: self.email.to_string(),
+            public_key: public_key.to_string(),
+            device_identifier: device_identifier.to_string(),
+            access_code: access_code.to_string(),
+            request_type: 0,
+        };
+
+        let response = self
+            .client
+            .post(url)
+            .json(&req)
+            .timeout(Duration::from_secs(Self::REQUEST_TIMEOUT_SECONDS))
+            .header("Device-Type", DEVICE_TYPE.to_string())
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response)
+    }
+
+    pub async fn get_auth_request_response(
+        &self,
+        auth_request_id: &str,
+        access_code: &str,
+    ) -> color_eyre::Result<AuthRequestResponse> {
+        let url = format!("{}/auth-requests/{auth_request_id}/response", self.api_base);
+
+        let response = self
+            .client
+            .get(url)
+            .query(&[("code", access_code)])
+            .timeout(Duration::from_secs(Self::REQUEST_TIMEOUT_SECONDS))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response)
+    }
+
+    async fn password_grant(
+        &self,
+        req: PasswordTokenRequest,
+    ) -> color_eyre::Result<PasswordLoginResponse> {
+        let (status, response) = self.send(req).await?;
+
+        if !status.is_success() {
+            if let Ok(error) = serde_json::from_str::<IdentityTokenErrorResponse>(&response) {
+                if error.two_factor_providers.is_some() {
+                    return Ok(PasswordLoginResponse::TwoFactorRequired(error));
+                }
+
+                if error.is_new_device_verification() {
+                    return Ok(PasswordLoginResponse::NewDeviceVerificationRequired);
+                }
+            }
+
+            return Err(Self::error_from_response(status, &response));
+        }
+
+        serde_json::from_str(&response)
+            .map(PasswordLoginResponse::Success)
+            .map_err(|_| eyre!("Unexpected response: {response}"))
+    }
+
+    // personal api keys skip two-step login and don't get a refresh token,
+    // so this is also how the access token is renewed for them
+    pub async fn api_key_login(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+        device: &Device,
     ) -> color_eyre::Result<IdentityTokenSuccessResponse> {
-        let req = PasswordTokenRequest::new(email, password);
+        let req = ClientCredentialsTokenRequest::new(client_id, client_secret, device);
         self.identity_connect(req).await
     }
 
@@ -36,27 +159,81 @@ impl<'a> IdentityClient<'a> {
         refresh_token: &str,
     ) -> color_eyre::Result<IdentityTokenRefreshResponse> {
         let req = RenewTokenRequest::new(refresh_token);
-        self.identity_connect(req).await
+        self.renew_connect(req).await
+    }
+
+    pub async fn renew_token_with_api_key(
+        &self,
+        client_id: &str,
+        client_secret: &str,
+        device: &Device,
+    ) -> color_eyre::Result<IdentityTokenRefreshResponse> {
+        let req = ClientCredentialsTokenRequest::new(client_id, client_secret, device);
+        self.renew_connect(req).await
+    }
+
+    // same as identity_connect, but with a friendlier error when the session is gone
+    async fn renew_connect<Req: serde::Serialize, Res: DeserializeOwned>(
+        &self,
+        request: Req,
+    ) -> color_eyre::Result<Res> {
+        let (status, response) = self
+            .send(request)
+            .await
+            .map_err(|e| eyre!("Error renewing the access token: {e}"))?;
+
+        if !status.is_success() {
+            if let Ok(error) = serde_json::from_str::<IdentityTokenErrorResponse>(&response) {
+                if error.is_revoked_session() {
+                    return Err(eyre!(
+                        "Your session has expired or was revoked. Please log in again using `bw-ssh-agent login`"
+                    ));
+                }
+            }
+
+            return Err(Self::error_from_response(status, &response));
+        }
+
+        serde_json::from_str::<Res>(&response).map_err(|_| eyre!("Unexpected response: {response}"))
     }
 
     async fn identity_connect<Req: serde::Serialize, Res: DeserializeOwned>(
         &self,
         request: Req,
     ) -> color_eyre::Result<Res> {
+        let (status, response) = self.send(request).await?;
+
+        if !status.is_success() {
+            return Err(Self::error_from_response(status, &response));
+        }
+
+        serde_json::from_str::<Res>(&response).map_err(|_| eyre!("Unexpected response: {response}"))
+    }
+
+    async fn send<Req: serde::Serialize>(
+        &self,
+        request: Req,
+    ) -> color_eyre::Result<(reqwest::StatusCode, String)> {
         let url = format!("{}/connect/token", self.base);
 
         let response = self
             .client
             .post(url)
             .form(&request)
+            .timeout(Duration::from_secs(Self::REQUEST_TIMEOUT_SECONDS))
             .header(reqwest::header::ACCEPT, "application/json")
             .header("Auth-Email", URL_SAFE_NO_PAD.encode(self.email))
             .send()
-            .await?
-            .error_for_status()?
-            .text()
             .await?;
 
-        serde_json::from_str::<Res>(&response).map_err(|_| eyre!("Unexpected response: {response}"))
+        let status = response.status();
+        Ok((status, response.text().await?))
+    }
+
+    fn error_from_response(status: reqwest::StatusCode, response: &str) -> color_eyre::Report {
+        match serde_json::from_str::<IdentityTokenErrorResponse>(response) {
+            Ok(error) => eyre!("{} ({status})", error.message()),
+            Err(_) => eyre!("Unexpected response ({status}): {response}"),
+        }
     }
 }
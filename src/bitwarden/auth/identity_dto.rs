@@ -1,5 +1,12 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
+use super::{device::Device, two_factor::TwoFactorRequest};
+
+// "SDK" in the official DeviceType enum
+pub const DEVICE_TYPE: u8 = 21;
+
 #[derive(Serialize, Debug)]
 pub struct PasswordTokenRequest {
     #[serde(rename = "grant_type")]
@@ -11,26 +18,91 @@ pub struct PasswordTokenRequest {
     #[serde(rename = "deviceIdentifier")]
     device_identifier: String,
     #[serde(rename = "deviceName")]
-    device_name: &'static str,
+    device_name: String,
     #[serde(rename = "username")]
     username: String,
     #[serde(rename = "password")]
     password: String,
     #[serde(rename = "scope")]
     scope: &'static str,
+    #[serde(rename = "twoFactorToken", skip_serializing_if = "Option::is_none")]
+    two_factor_token: Option<String>,
+    #[serde(rename = "twoFactorProvider", skip_serializing_if = "Option::is_none")]
+    two_factor_provider: Option<u8>,
+    #[serde(rename = "twoFactorRemember", skip_serializing_if = "Option::is_none")]
+    two_factor_remember: Option<u8>,
+    // id of the approved auth request, the password is the access code then
+    #[serde(rename = "authRequest", skip_serializing_if = "Option::is_none")]
+    auth_request: Option<String>,
+    // emailed by the server when logging in from an unknown device without two-step login
+    #[serde(rename = "newDeviceOtp", skip_serializing_if = "Option::is_none")]
+    new_device_otp: Option<String>,
 }
 
 impl PasswordTokenRequest {
-    pub fn new(username: &str, password: &str) -> Self {
+    pub fn new(
+        username: &str,
+        password: &str,
+        device: &Device,
+        two_factor: Option<&TwoFactorRequest>,
+    ) -> Self {
         Self {
             grant_type: "password",
             client_id: "browser",
-            device_type: 21,
-            device_identifier: uuid::Uuid::new_v4().to_string(),
-            device_name: "bw-ssh-agent",
+            device_type: DEVICE_TYPE,
+            device_identifier: device.identifier.clone(),
+            device_name: device.name.clone(),
             username: username.to_string(),
             password: password.to_string(),
             scope: "api offline_access",
+            two_factor_token: two_factor.map(|t| t.token.clone()),
+            two_factor_provider: two_factor.map(|t| t.provider as u8),
+            two_factor_remember: two_factor.map(|t| t.remember as u8),
+            auth_request: None,
+            new_device_otp: None,
+        }
+    }
+
+    pub fn with_new_device_otp(mut self, otp: Option<&str>) -> Self {
+        self.new_device_otp = otp.map(str::to_string);
+        self
+    }
+
+    pub fn with_auth_request(mut self, auth_request_id: &str) -> Self {
+        self.auth_request = Some(auth_request_id.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClientCredentialsTokenRequest {
+    #[serde(rename = "grant_type")]
+    grant_type: &'static str,
+    // `user.<uuid>`
+    #[serde(rename = "client_id")]
+    client_id: String,
+    #[serde(rename = "client_secret")]
+    client_secret: String,
+    #[serde(rename = "deviceType")]
+    device_type: u8,
+    #[serde(rename = "deviceIdentifier")]
+    device_identifier: String,
+    #[serde(rename = "deviceName")]
+    device_name: String,
+    #[serde(rename = "scope")]
+    scope: &'static str,
+}
+
+impl ClientCredentialsTokenRequest {
+    pub fn new(client_id: &str, client_secret: &str, device: &Device) -> Self {
+        Self {
+            grant_type: "client_credentials",
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            device_type: DEVICE_TYPE,
+            device_identifier: device.identifier.clone(),
+            device_name: device.name.clone(),
+            scope: "api",
         }
     }
 }
@@ -58,6 +130,7 @@ impl RenewTokenRequest {
 #[derive(Serialize, Debug)]
 pub enum TokenConnectRequest {
     Password(PasswordTokenRequest),
+    ClientCredentials(ClientCredentialsTokenRequest),
     Renew(RenewTokenRequest),
 }
 
@@ -79,13 +152,93 @@ pub struct IdentityTokenSuccessResponse {
     pub reset_master_password: bool,
     #[serde(rename = "access_token")]
     pub access_token: String,
+    // not returned for api key logins
     #[serde(rename = "refresh_token")]
-    pub refresh_token: String,
+    pub refresh_token: Option<String>,
     #[serde(rename = "expires_in")]
     pub expires_in: u64,
     pub scope: String,
     #[serde(rename = "token_type")]
     pub token_type: String,
+    // only returned when twoFactorRemember was set
+    #[serde(rename = "TwoFactorToken")]
+    pub two_factor_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IdentityTokenErrorResponse {
+    pub error: String,
+    pub error_description: Option<String>,
+    #[serde(rename = "ErrorModel", alias = "errorModel")]
+    pub error_model: Option<IdentityErrorModel>,
+    // provider type -> provider-specific data (e.g. the masked email address)
+    #[serde(rename = "TwoFactorProviders2")]
+    pub two_factor_providers: Option<HashMap<String, Option<serde_json::Value>>>,
+    #[serde(rename = "SsoEmail2faSessionToken")]
+    pub sso_email_2fa_session_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IdentityErrorModel {
+    #[serde(rename = "Message", alias = "message")]
+    pub message: String,
+}
+
+impl IdentityTokenErrorResponse {
+    pub fn is_new_device_verification(&self) -> bool {
+        self.error_description
+            .as_deref()
+            .is_some_and(|description| {
+                description.eq_ignore_ascii_case("new device verification required")
+            })
+    }
+
+    // refresh token (or api key) is no longer accepted
+    pub fn is_revoked_session(&self) -> bool {
+        self.error == "invalid_grant" || self.error == "invalid_client"
+    }
+
+    pub fn message(&self) -> &str {
+        match (&self.error_model, &self.error_description) {
+            (Some(model), _) => &model.message,
+            (None, Some(description)) => description,
+            (None, None) => &self.error,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthRequestCreateRequest {
+    pub email: String,
+    // spki der, base64
+    pub public_key: String,
+    pub device_identifier: String,
+    pub access_code: String,
+    // AuthenticateAndUnlock
+    #[serde(rename = "type")]
+    pub request_type: u8,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuthRequestResponse {
+    #[serde(rename = "id", alias = "Id")]
+    pub id: String,
+    // user key (or master key for older clients), encrypted with our public key
+    #[serde(rename = "key", alias = "Key")]
+    pub key: Option<String>,
+    // only set by clients that send the master key instead of the user key
+    #[serde(rename = "masterPasswordHash", alias = "MasterPasswordHash")]
+    pub master_password_hash: Option<String>,
+    // null until the request is answered
+    #[serde(rename = "requestApproved", alias = "RequestApproved")]
+    pub request_approved: Option<bool>,
+}
+
+pub enum PasswordLoginResponse {
+    Success(Box<IdentityTokenSuccessResponse>),
+    TwoFactorRequired(IdentityTokenErrorResponse),
+    NewDeviceVerificationRequired,
 }
 
 #[derive(Deserialize, Debug)]
@@ -1,4 +1,6 @@
+pub mod device;
 pub mod identity;
 pub mod identity_dto;
 pub mod prelogin;
 pub mod token;
+pub mod two_factor;
@@ -1,8 +1,9 @@
+use color_eyre::eyre::eyre;
 use serde::Deserialize;
 use serde_json::json;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
-use crate::bitwarden::config::ConfigResponseModel;
+use crate::bitwarden::{config::ConfigResponseModel, crypto::KdfParams};
 
 #[derive(Serialize_repr, Deserialize_repr, PartialEq, Clone, Debug)]
 #[repr(u8)]
@@ -23,6 +24,28 @@ pub struct PreloginResponseModel {
     pub kdf_parallelism: Option<u32>,
 }
 
+impl PreloginResponseModel {
+    pub fn kdf_params(&self) -> color_eyre::Result<KdfParams> {
+        match self.kdf {
+            KdfType::Pbkdf2Sha256 => Ok(KdfParams::Pbkdf2Sha256 {
+                iterations: self.kdf_iterations,
+            }),
+            KdfType::Argon2id => {
+                let (Some(memory), Some(parallelism)) = (self.kdf_memory, self.kdf_parallelism)
+                else {
+                    return Err(eyre!("Server did not return argon2 memory and parallelism"));
+                };
+
+                Ok(KdfParams::Argon2id {
+                    iterations: self.kdf_iterations,
+                    memory,
+                    parallelism,
+                })
+            }
+        }
+    }
+}
+
 pub async fn bw_prelogin(
     client: &reqwest::Client,
     config: &ConfigResponseModel,
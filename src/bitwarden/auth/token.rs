@@ -1,51 +1,152 @@
+use std::time::Duration;
+
 use color_eyre::eyre::eyre;
 
-use crate::{database::Database, utils::get_current_unix_timestamp};
+use crate::{
+    database::{AuthDto, Database},
+    utils::get_current_unix_timestamp,
+};
 
-use super::identity::IdentityClient;
+use super::{device::Device, identity::IdentityClient};
 
 pub struct TokenManager<'a> {
     db: &'a Database,
     identity: &'a IdentityClient<'a>,
     access_token: String,
-    refresh_token: &'a str,
+    refresh_token: String,
     expires_at: u64,
+    // (client_id, client_secret) for api key logins
+    api_key: Option<(String, String)>,
+}
+
+// releases the renewal lease when dropped, which also covers a cancelled renewal
+struct RenewalLease<'a> {
+    db: &'a Database,
+    holder: String,
+}
+
+impl Drop for RenewalLease<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.release_token_lease(&self.holder) {
+            eprintln!("Error releasing the token renewal lease: {e}");
+        }
+    }
 }
 
 impl<'a> TokenManager<'a> {
     // official implementation uses 5 * 60, but I think 60 seconds should be enough
     const TOKEN_RENEW_MARGIN_SECONDS: u64 = 60;
+    // longer than the identity request timeout, so that it only expires if the holder is gone
+    const LEASE_SECONDS: u64 = 60;
+    const LEASE_POLL_INTERVAL_MS: u64 = 250;
 
     pub fn new(
         db: &'a Database,
         identity: &'a IdentityClient<'a>,
         // an initial data known from auth to avoid fetching from the db twice
-        access_token: String,
-        refresh_token: &'a str,
-        expires_at: u64,
+        auth: &AuthDto,
     ) -> Self {
-        Self {
+        let mut manager = Self {
             db,
             identity,
-            access_token,
-            refresh_token,
-            expires_at,
-        }
+            access_token: String::new(),
+            refresh_token: String::new(),
+            expires_at: 0,
+            api_key: None,
+        };
+        manager.load(auth);
+
+        manager
+    }
+
+    fn load(&mut self, auth: &AuthDto) {
+        self.access_token = auth.access_token.clone();
+        self.refresh_token = auth.refresh_token.clone();
+        self.expires_at = auth.expires_at;
+        self.api_key = match (&auth.client_id, &auth.client_secret) {
+            (Some(client_id), Some(client_secret)) => {
+                Some((client_id.clone(), client_secret.clone()))
+            }
+            _ => None,
+        };
+    }
+
+    fn needs_renewal(&self) -> bool {
+        get_current_unix_timestamp() + Self::TOKEN_RENEW_MARGIN_SECONDS >= self.expires_at
     }
 
     pub async fn get_access_token(&mut self) -> color_eyre::Result<&str> {
-        if get_current_unix_timestamp() > self.expires_at - Self::TOKEN_RENEW_MARGIN_SECONDS {
-            let res = self.identity.renew_token(self.refresh_token).await
-                .map_err(|e| eyre!("Error renewing the access token. Try logging in using \"bw-ssh-agent login\"{e}"))?;
+        if self.needs_renewal() {
+            // the daemon and the cli share the refresh token. only one of them may renew at a time,
+            // since with rotation the second one would get the session revoked
+            let _lease = self.acquire_lease().await?;
+            self.renew().await?;
+        }
 
-            self.access_token = res.access_token;
-            self.expires_at = get_current_unix_timestamp() + res.expires_in;
+        Ok(self.access_token.as_str())
+    }
+
+    async fn acquire_lease(&self) -> color_eyre::Result<RenewalLease<'a>> {
+        let holder = uuid::Uuid::new_v4().to_string();
+        // the current holder either finishes or its lease expires by then
+        let deadline = get_current_unix_timestamp() + Self::LEASE_SECONDS + 5;
+
+        loop {
+            let now = get_current_unix_timestamp();
+            if self
+                .db
+                .try_acquire_token_lease(&holder, now, now + Self::LEASE_SECONDS)?
+            {
+                return Ok(RenewalLease {
+                    db: self.db,
+                    holder,
+                });
+            }
 
-            self.db.update_auth(&self.access_token, self.expires_at)?;
+            if now >= deadline {
+                return Err(eyre!(
+                    "Timed out waiting for another process to renew the access token"
+                ));
+            }
 
-            println!("Renewed access token");
+            tokio::time::sleep(Duration::from_millis(Self::LEASE_POLL_INTERVAL_MS)).await;
         }
+    }
 
-        Ok(self.access_token.as_str())
+    async fn renew(&mut self) -> color_eyre::Result<()> {
+        // the other process might have renewed the token while we were waiting for the lease
+        let Some(auth) = self.db.get_auth()? else {
+            return Err(eyre!(
+                "Not logged in. Please run `bw-ssh-agent login` first."
+            ));
+        };
+        self.load(&auth);
+
+        if !self.needs_renewal() {
+            return Ok(());
+        }
+
+        let res = match self.api_key {
+            Some((ref client_id, ref client_secret)) => {
+                self.identity
+                    .renew_token_with_api_key(client_id, client_secret, &Device::load(self.db)?)
+                    .await?
+            }
+            None => self.identity.renew_token(&self.refresh_token).await?,
+        };
+
+        self.access_token = res.access_token;
+        self.expires_at = get_current_unix_timestamp() + res.expires_in;
+        // servers may rotate the refresh token, in which case the old one stops working
+        if let Some(refresh_token) = res.refresh_token {
+            self.refresh_token = refresh_token;
+        }
+
+        self.db
+            .update_auth(&self.access_token, &self.refresh_token, self.expires_at)?;
+
+        println!("Renewed access token");
+
+        Ok(())
     }
 }
@@ -0,0 +1,88 @@
+use std::fmt;
+
+use serde_json::json;
+
+use crate::bitwarden::config::ConfigResponseModel;
+
+// see TwoFactorProviderType in the official server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TwoFactorProvider {
+    Authenticator = 0,
+    Email = 1,
+    Duo = 2,
+    YubiKey = 3,
+    U2f = 4,
+    Remember = 5,
+    OrganizationDuo = 6,
+    WebAuthn = 7,
+}
+
+impl TwoFactorProvider {
+    pub fn from_id(id: &str) -> Option<Self> {
+        Some(match id {
+            "0" => Self::Authenticator,
+            "1" => Self::Email,
+            "2" => Self::Duo,
+            "3" => Self::YubiKey,
+            "4" => Self::U2f,
+            "5" => Self::Remember,
+            "6" => Self::OrganizationDuo,
+            "7" => Self::WebAuthn,
+            _ => return None,
+        })
+    }
+
+    // the rest require a browser
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Self::Authenticator | Self::Email | Self::YubiKey)
+    }
+}
+
+impl fmt::Display for TwoFactorProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Authenticator => "Authenticator app",
+            Self::Email => "Email",
+            Self::Duo => "Duo",
+            Self::YubiKey => "YubiKey OTP",
+            Self::U2f => "FIDO U2F",
+            Self::Remember => "Remembered device",
+            Self::OrganizationDuo => "Duo (organization)",
+            Self::WebAuthn => "FIDO2 WebAuthn",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TwoFactorRequest {
+    pub provider: TwoFactorProvider,
+    pub token: String,
+    pub remember: bool,
+}
+
+pub async fn bw_send_email_login(
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    email: &str,
+    master_password_hash: &str,
+    device_identifier: &str,
+    sso_email_2fa_session_token: Option<&str>,
+) -> color_eyre::Result<()> {
+    let url = format!("{}/two-factor/send-email-login", config.environment.api);
+
+    let body = json!({
+        "email": email,
+        "masterPasswordHash": master_password_hash,
+        "deviceIdentifier": device_identifier,
+        "ssoEmail2FaSessionToken": sso_email_2fa_session_token,
+    });
+
+    client
+        .post(url)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
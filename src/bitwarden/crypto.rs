@@ -1,19 +1,160 @@
+use std::fmt;
+
 use aes::cipher::{block_padding::Pkcs7, generic_array::GenericArray, BlockDecryptMut as _};
 use base64::{prelude::BASE64_STANDARD, Engine};
 use hkdf::Hkdf;
 use hmac::Mac;
 use pbkdf2::hmac::Hmac;
-use sha2::Sha256;
+use rsa::{pkcs8::DecodePrivateKey, Oaep};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum KdfParams {
+    Pbkdf2Sha256 {
+        iterations: u32,
+    },
+    Argon2id {
+        iterations: u32,
+        // in MiB, same as the server returns it
+        memory: u32,
+        parallelism: u32,
+    },
+}
+
+impl KdfParams {
+    // same as the bounds the official clients accept at prelogin
+    const PBKDF2_MIN_ITERATIONS: u32 = 5000;
+    const PBKDF2_MAX_ITERATIONS: u32 = 2_000_000;
+    const ARGON2_MIN_ITERATIONS: u32 = 2;
+    const ARGON2_MAX_ITERATIONS: u32 = 10;
+    const ARGON2_MIN_MEMORY: u32 = 16;
+    const ARGON2_MAX_MEMORY: u32 = 1024;
+    const ARGON2_MIN_PARALLELISM: u32 = 1;
+    const ARGON2_MAX_PARALLELISM: u32 = 16;
+
+    // protects against a server handing out parameters that make the master password hash
+    // cheap to brute-force, or so expensive that hashing it hangs or runs out of memory
+    pub fn check_bounds(&self) -> color_eyre::Result<()> {
+        let (secure, feasible) = match *self {
+            KdfParams::Pbkdf2Sha256 { iterations } => (
+                iterations >= Self::PBKDF2_MIN_ITERATIONS,
+                iterations <= Self::PBKDF2_MAX_ITERATIONS,
+            ),
+            KdfParams::Argon2id {
+                iterations,
+                memory,
+                parallelism,
+            } => (
+                iterations >= Self::ARGON2_MIN_ITERATIONS
+                    && memory >= Self::ARGON2_MIN_MEMORY
+                    && parallelism >= Self::ARGON2_MIN_PARALLELISM,
+                iterations <= Self::ARGON2_MAX_ITERATIONS
+                    && memory <= Self::ARGON2_MAX_MEMORY
+                    && parallelism <= Self::ARGON2_MAX_PARALLELISM,
+            ),
+        };
+
+        if !secure {
+            return Err(color_eyre::eyre::eyre!(
+                "Server returned insecure KDF parameters ({self}), refusing to log in"
+            ));
+        }
+
+        if !feasible {
+            return Err(color_eyre::eyre::eyre!(
+                "Server returned KDF parameters that are too expensive to compute ({self}), refusing to log in"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // argon2id is considered stronger than any pbkdf2 configuration
+    pub fn is_weaker_than(&self, other: &KdfParams) -> bool {
+        match (self, other) {
+            (
+                KdfParams::Pbkdf2Sha256 { iterations },
+                KdfParams::Pbkdf2Sha256 {
+                    iterations: other_iterations,
+                },
+            ) => iterations < other_iterations,
+            (
+                KdfParams::Argon2id {
+                    iterations,
+                    memory,
+                    parallelism,
+                },
+                KdfParams::Argon2id {
+                    iterations: other_iterations,
+                    memory: other_memory,
+                    parallelism: other_parallelism,
+                },
+            ) => {
+                iterations < other_iterations
+                    || memory < other_memory
+                    || parallelism < other_parallelism
+            }
+            (KdfParams::Pbkdf2Sha256 { .. }, KdfParams::Argon2id { .. }) => true,
+            (KdfParams::Argon2id { .. }, KdfParams::Pbkdf2Sha256 { .. }) => false,
+        }
+    }
+}
 
-pub fn make_master_key(password: &str, salt: &str, iterations: u32) -> color_eyre::Result<Vec<u8>> {
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KdfParams::Pbkdf2Sha256 { iterations } => {
+                write!(f, "PBKDF2-SHA256, {iterations} iterations")
+            }
+            KdfParams::Argon2id {
+                iterations,
+                memory,
+                parallelism,
+            } => write!(
+                f,
+                "Argon2id, {iterations} iterations, {memory} MiB, parallelism {parallelism}"
+            ),
+        }
+    }
+}
+
+// the salt is the (lowercased) email
+pub fn make_master_key(password: &str, salt: &str, kdf: &KdfParams) -> color_eyre::Result<Vec<u8>> {
     let mut master_key = vec![0u8; 32];
 
-    pbkdf2::pbkdf2::<Hmac<Sha256>>(
-        password.as_bytes(),
-        salt.as_bytes(),
-        iterations,
-        &mut master_key,
-    )?;
+    match *kdf {
+        KdfParams::Pbkdf2Sha256 { iterations } => {
+            pbkdf2::pbkdf2::<Hmac<Sha256>>(
+                password.as_bytes(),
+                salt.as_bytes(),
+                iterations,
+                &mut master_key,
+            )?;
+        }
+        KdfParams::Argon2id {
+            iterations,
+            memory,
+            parallelism,
+        } => {
+            // bitwarden hashes the salt first, since argon2 needs a salt of fixed length
+            let salt = Sha256::digest(salt.as_bytes());
+
+            let params = argon2::Params::new(
+                memory
+                    .checked_mul(1024)
+                    .ok_or_else(|| color_eyre::eyre::eyre!("Argon2 memory is too large"))?,
+                iterations,
+                parallelism,
+                Some(master_key.len()),
+            )
+            .map_err(|e| color_eyre::eyre::eyre!("Invalid argon2 parameters: {e}"))?;
+
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password_into(password.as_bytes(), &salt, &mut master_key)
+                .map_err(|e| color_eyre::eyre::eyre!("Argon2 failed: {e}"))?;
+        }
+    }
 
     Ok(master_key)
 }
@@ -41,8 +182,6 @@ pub fn hkdf_expand_key(master_key: &[u8]) -> color_eyre::Result<[u8; 64]> {
 }
 
 pub fn bw_decrypt_encstr(key: &[u8], encrypted_data: &str) -> color_eyre::Result<Vec<u8>> {
-    let (enc_key, mac_key) = (&key[..32], &key[32..]);
-
     let header_pieces = encrypted_data.split(".").collect::<Vec<&str>>();
 
     let (enc_type, enc_pieces) = if header_pieces.len() == 1 {
@@ -70,20 +209,158 @@ pub fn bw_decrypt_encstr(key: &[u8], encrypted_data: &str) -> color_eyre::Result
     let mut data = BASE64_STANDARD.decode(enc_pieces[1])?;
     let mac = BASE64_STANDARD.decode(enc_pieces[2])?;
 
+    aes_cbc_hmac_decrypt(key, &iv, &mut data, &mac)
+}
+
+// decrypts an rsa-encrypted EncString (e.g. organization keys) using the user private key (pkcs#8 der)
+pub fn bw_decrypt_rsa_encstr(
+    private_key: &[u8],
+    encrypted_data: &str,
+) -> color_eyre::Result<Vec<u8>> {
+    let Some((enc_type, enc_pieces)) = encrypted_data.split_once('.') else {
+        return Err(color_eyre::eyre::eyre!("Invalid encrypted data format"));
+    };
+
+    // the deprecated *_HmacSha256 variants carry a mac, but there's no key to verify it with
+    // (official clients ignore it too), so only the data piece is used
+    let data = BASE64_STANDARD.decode(enc_pieces.split('|').next().unwrap_or_default())?;
+    let private_key = rsa::RsaPrivateKey::from_pkcs8_der(private_key)?;
+
+    let plaintext = match enc_type.parse::<u32>()? {
+        // Rsa2048_OaepSha256_B64, Rsa2048_OaepSha256_HmacSha256_B64
+        3 | 5 => private_key.decrypt(Oaep::new::<Sha256>(), &data)?,
+        // Rsa2048_OaepSha1_B64, Rsa2048_OaepSha1_HmacSha256_B64
+        4 | 6 => private_key.decrypt(Oaep::new::<Sha1>(), &data)?,
+        enc_type => {
+            return Err(color_eyre::eyre::eyre!(
+                "Unsupported encryption type {}",
+                enc_type
+            ))
+        }
+    };
+
+    Ok(plaintext)
+}
+
+// decrypts an EncArrayBuffer, which is used for attachments
+pub fn bw_decrypt_encbuf(key: &[u8], encrypted_data: &[u8]) -> color_eyre::Result<Vec<u8>> {
+    // enc type (1 byte) + iv (16 bytes) + mac (32 bytes) + data
+    if encrypted_data.len() < 49 {
+        return Err(color_eyre::eyre::eyre!("Invalid encrypted data format"));
+    }
+
+    // we only support AesCbc256_HmacSha256_B64 for now
+    let enc_type = encrypted_data[0];
+    if enc_type != 2 {
+        return Err(color_eyre::eyre::eyre!(
+            "Unsupported encryption type {}",
+            enc_type
+        ));
+    }
+
+    let iv = &encrypted_data[1..17];
+    let mac = &encrypted_data[17..49];
+    let mut data = encrypted_data[49..].to_vec();
+
+    aes_cbc_hmac_decrypt(key, iv, &mut data, mac)
+}
+
+fn aes_cbc_hmac_decrypt(
+    key: &[u8],
+    iv: &[u8],
+    data: &mut [u8],
+    mac: &[u8],
+) -> color_eyre::Result<Vec<u8>> {
+    let (enc_key, mac_key) = (&key[..32], &key[32..]);
+
     // verify the MAC
     let mut mac_expected = Hmac::<Sha256>::new_from_slice(mac_key)?;
-    mac_expected.update(iv.as_slice());
-    mac_expected.update(data.as_slice());
-    mac_expected.verify_slice(mac.as_slice())?;
+    mac_expected.update(iv);
+    mac_expected.update(data);
+    mac_expected.verify_slice(mac)?;
 
     // decrypt the content
     let key_enc = GenericArray::from_slice(enc_key);
-    let iv = GenericArray::from_slice(iv.as_slice());
+    let iv = GenericArray::from_slice(iv);
     let cipher = <cbc::Decryptor<aes::Aes256> as aes::cipher::KeyIvInit>::new(key_enc, iv);
-    let plaintext = cipher.decrypt_padded_mut::<Pkcs7>(&mut data);
+    let plaintext = cipher.decrypt_padded_mut::<Pkcs7>(data);
 
     match plaintext {
         Ok(plaintext) => Ok(plaintext.to_vec()),
         Err(e) => Err(color_eyre::eyre::eyre!(e)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kdf_bounds() {
+        let pbkdf2 = |iterations| KdfParams::Pbkdf2Sha256 { iterations };
+        let argon2 = |iterations, memory, parallelism| KdfParams::Argon2id {
+            iterations,
+            memory,
+            parallelism,
+        };
+
+        assert!(pbkdf2(600_000).check_bounds().is_ok());
+        assert!(pbkdf2(4999).check_bounds().is_err());
+        assert!(pbkdf2(2_000_001).check_bounds().is_err());
+
+        assert!(argon2(3, 64, 4).check_bounds().is_ok());
+        assert!(argon2(10, 1024, 16).check_bounds().is_ok());
+        assert!(argon2(1, 64, 4).check_bounds().is_err());
+        assert!(argon2(3, 15, 4).check_bounds().is_err());
+        assert!(argon2(3, 64, 0).check_bounds().is_err());
+        assert!(argon2(11, 64, 4).check_bounds().is_err());
+        assert!(argon2(3, 1025, 4).check_bounds().is_err());
+        assert!(argon2(3, 64, 17).check_bounds().is_err());
+    }
+
+    // vectors from the master key tests of the official sdk
+
+    #[test]
+    fn master_key_pbkdf2() {
+        let kdf = KdfParams::Pbkdf2Sha256 { iterations: 10000 };
+        let master_key = make_master_key("67t9b5g67$%Dh89n", "test_key", &kdf).unwrap();
+
+        assert_eq!(
+            master_key,
+            [
+                31, 79, 104, 226, 150, 71, 177, 90, 194, 80, 172, 209, 17, 129, 132, 81, 138, 167,
+                69, 167, 254, 149, 2, 27, 39, 197, 64, 42, 22, 195, 86, 75
+            ]
+        );
+    }
+
+    #[test]
+    fn master_key_argon2id() {
+        // memory is given in MiB, and the salt is sha256(email)
+        let kdf = KdfParams::Argon2id {
+            iterations: 4,
+            memory: 32,
+            parallelism: 2,
+        };
+        let master_key = make_master_key("67t9b5g67$%Dh89n", "test_key", &kdf).unwrap();
+
+        assert_eq!(
+            master_key,
+            [
+                207, 240, 225, 177, 162, 19, 163, 76, 98, 106, 179, 175, 224, 9, 17, 240, 20, 147,
+                237, 47, 246, 150, 141, 184, 62, 225, 131, 242, 51, 53, 225, 242
+            ]
+        );
+    }
+
+    #[test]
+    fn master_key_hash() {
+        let kdf = KdfParams::Pbkdf2Sha256 { iterations: 100000 };
+        let master_key = make_master_key("asdfasdf", "test@bitwarden.com", &kdf).unwrap();
+
+        assert_eq!(
+            make_master_key_hash(&master_key, "asdfasdf").unwrap(),
+            "wmyadRMyBZOH7P/a/ucTCbSghKgdzDpPqUnu/DAVtSw="
+        );
+    }
+}
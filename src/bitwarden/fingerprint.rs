@@ -0,0 +1,89 @@
+use color_eyre::eyre::eyre;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+// https://www.eff.org/files/2016/07/18/eff_large_wordlist.txt, fetched by `cargo make build`
+const EFF_LARGE_WORDLIST: &str = include_str!("../../assets/eff_large_wordlist.txt");
+const EFF_LARGE_WORDLIST_LENGTH: usize = 7776;
+// same as the official clients
+const MINIMUM_ENTROPY_BITS: f64 = 64.0;
+
+fn wordlist() -> color_eyre::Result<Vec<&'static str>> {
+    // each line is the dice roll followed by a tab and the word
+    let words = EFF_LARGE_WORDLIST
+        .lines()
+        .map(|line| line.split_once('\t').map(|(_, word)| word.trim()))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| eyre!("Invalid wordlist"))?;
+
+    if words.len() != EFF_LARGE_WORDLIST_LENGTH {
+        return Err(eyre!("Invalid wordlist"));
+    }
+
+    Ok(words)
+}
+
+// the phrase the bitwarden apps show for a public key, e.g. when approving a login request.
+// the key is hashed together with the account email, so the phrase differs between accounts
+pub fn fingerprint_phrase(material: &str, public_key: &[u8]) -> color_eyre::Result<String> {
+    let key_fingerprint = Sha256::digest(public_key);
+
+    let mut user_fingerprint = [0u8; 32];
+    Hkdf::<Sha256>::from_prk(&key_fingerprint)
+        .map_err(|_| eyre!("Invalid key fingerprint"))?
+        .expand(material.as_bytes(), &mut user_fingerprint)
+        .map_err(|_| eyre!("Failed to expand the key fingerprint"))?;
+
+    let words = wordlist()?;
+    let entropy_per_word = (words.len() as f64).log2();
+    let word_count = (MINIMUM_ENTROPY_BITS / entropy_per_word).ceil() as usize;
+
+    // the fingerprint is treated as a big-endian number, and each word takes a digit of it
+    let mut phrase = Vec::with_capacity(word_count);
+    for _ in 0..word_count {
+        let index = divide_in_place(&mut user_fingerprint, words.len() as u32);
+        phrase.push(words[index as usize]);
+    }
+
+    Ok(phrase.join("-"))
+}
+
+// long division of a big-endian number, returns the remainder
+fn divide_in_place(number: &mut [u8], divisor: u32) -> u32 {
+    let mut remainder = 0u32;
+
+    for byte in number.iter_mut() {
+        let current = (remainder << 8) | *byte as u32;
+        *byte = (current / divisor) as u8;
+        remainder = current % divisor;
+    }
+
+    remainder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wordlist_is_complete() {
+        let words = wordlist().unwrap();
+        assert_eq!(words.len(), EFF_LARGE_WORDLIST_LENGTH);
+
+        // the lines are numbered by dice rolls, 11111 to 66666
+        for (i, line) in EFF_LARGE_WORDLIST.lines().enumerate() {
+            let expected = (0..5)
+                .rev()
+                .map(|digit| char::from(b'1' + (i / 6usize.pow(digit) % 6) as u8))
+                .collect::<String>();
+            assert!(line.starts_with(&format!("{expected}\t")), "{line}");
+        }
+    }
+
+    #[test]
+    fn divide_big_endian() {
+        let mut number = [0x01, 0x00, 0x00];
+        assert_eq!(divide_in_place(&mut number, 7776), 65536 % 7776);
+        assert_eq!(number, [0, 0, (65536 / 7776) as u8]);
+    }
+}
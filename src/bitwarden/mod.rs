@@ -2,4 +2,6 @@ pub mod auth;
 pub mod config;
 pub mod constants;
 pub mod crypto;
+pub mod fingerprint;
+pub mod notifications;
 pub mod sync;
@@ -0,0 +1,276 @@
+use std::{collections::VecDeque, time::Duration};
+
+use color_eyre::eyre::eyre;
+use futures_util::{SinkExt, StreamExt};
+use rmpv::Value;
+use tokio::{
+    net::TcpStream,
+    time::{Instant, Interval},
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use super::{config::ConfigResponseModel, constants::USER_AGENT};
+
+// see PushType in the official server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PushType {
+    SyncCipherUpdate = 0,
+    SyncCipherCreate = 1,
+    SyncLoginDelete = 2,
+    SyncFolderDelete = 3,
+    SyncCiphers = 4,
+    SyncVault = 5,
+    SyncOrgKeys = 6,
+    SyncFolderCreate = 7,
+    SyncFolderUpdate = 8,
+    SyncCipherDelete = 9,
+    SyncSettings = 10,
+    LogOut = 11,
+}
+
+impl PushType {
+    pub fn from_id(id: u64) -> Option<Self> {
+        Some(match id {
+            0 => Self::SyncCipherUpdate,
+            1 => Self::SyncCipherCreate,
+            2 => Self::SyncLoginDelete,
+            3 => Self::SyncFolderDelete,
+            4 => Self::SyncCiphers,
+            5 => Self::SyncVault,
+            6 => Self::SyncOrgKeys,
+            7 => Self::SyncFolderCreate,
+            8 => Self::SyncFolderUpdate,
+            9 => Self::SyncCipherDelete,
+            10 => Self::SyncSettings,
+            11 => Self::LogOut,
+            // sends, auth requests etc
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PushNotification {
+    pub push_type: PushType,
+    // id of the item for cipher notifications
+    pub id: Option<String>,
+}
+
+// signalr hub protocol, see https://github.com/dotnet/aspnetcore/blob/main/src/SignalR/docs/specs/HubProtocol.md
+const INVOCATION_MESSAGE: u64 = 1;
+const CLOSE_MESSAGE: u64 = 7;
+const RECORD_SEPARATOR: u8 = 0x1e;
+// the official server also speaks json, but vaultwarden only supports messagepack
+const HANDSHAKE: &str = "{\"protocol\":\"messagepack\",\"version\":1}\x1e";
+// length-prefixed [6]
+const PING: &[u8] = &[0x02, 0x91, 0x06];
+
+pub struct NotificationsClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    queue: VecDeque<PushNotification>,
+    ping: Interval,
+    last_received: Instant,
+}
+
+impl NotificationsClient {
+    const PING_INTERVAL_SECONDS: u64 = 15;
+    // the server pings us every 15 seconds as well, so after this long the connection is dead
+    const TIMEOUT_SECONDS: u64 = 60;
+
+    pub async fn connect(
+        config: &ConfigResponseModel,
+        access_token: &str,
+    ) -> color_eyre::Result<Self> {
+        let mut url = reqwest::Url::parse(&format!("{}/hub", config.environment.notifications))?;
+        let scheme = match url.scheme() {
+            "https" => "wss",
+            "http" => "ws",
+            scheme => return Err(eyre!("Unsupported notifications url scheme: {scheme}")),
+        };
+        url.set_scheme(scheme)
+            .map_err(|_| eyre!("Invalid notifications url: {url}"))?;
+        url.query_pairs_mut()
+            .append_pair("access_token", access_token);
+
+        let mut request = url.as_str().into_client_request()?;
+        request
+            .headers_mut()
+            .insert("User-Agent", HeaderValue::from_static(USER_AGENT));
+
+        let (stream, _) = tokio::time::timeout(
+            Duration::from_secs(Self::TIMEOUT_SECONDS),
+            connect_async(request),
+        )
+        .await
+        .map_err(|_| eyre!("Timed out connecting to the notifications hub"))??;
+
+        let period = Duration::from_secs(Self::PING_INTERVAL_SECONDS);
+        let mut client = Self {
+            stream,
+            queue: VecDeque::new(),
+            ping: tokio::time::interval_at(Instant::now() + period, period),
+            last_received: Instant::now(),
+        };
+        client.handshake().await?;
+
+        Ok(client)
+    }
+
+    async fn handshake(&mut self) -> color_eyre::Result<()> {
+        self.stream
+            .send(Message::Text(HANDSHAKE.to_string()))
+            .await?;
+
+        let data = loop {
+            let message = tokio::time::timeout(
+                Duration::from_secs(Self::TIMEOUT_SECONDS),
+                self.stream.next(),
+            )
+            .await
+            .map_err(|_| eyre!("Timed out waiting for the notifications hub handshake"))?;
+
+            match message {
+                Some(Ok(Message::Text(text))) => break text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => break data,
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(eyre!("Notifications hub closed the connection"))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
+        };
+
+        // {}\x1e, or {"error": "..."}\x1e. messages may follow right away
+        let Some(end) = data.iter().position(|&b| b == RECORD_SEPARATOR) else {
+            return Err(eyre!("Unexpected notifications hub handshake response"));
+        };
+        let response = serde_json::from_slice::<serde_json::Value>(&data[..end])?;
+        if let Some(error) = response.get("error") {
+            return Err(eyre!("Notifications hub handshake failed: {error}"));
+        }
+
+        self.parse_frame(&data[end + 1..])
+    }
+
+    // waits for the next notification we know about, keeping the connection alive meanwhile
+    pub async fn next(&mut self) -> color_eyre::Result<PushNotification> {
+        loop {
+            if let Some(notification) = self.queue.pop_front() {
+                return Ok(notification);
+            }
+
+            tokio::select! {
+                _ = self.ping.tick() => {
+                    if self.last_received.elapsed() > Duration::from_secs(Self::TIMEOUT_SECONDS) {
+                        return Err(eyre!("Notifications hub stopped responding"));
+                    }
+
+                    self.stream.send(Message::Binary(PING.to_vec())).await?;
+                }
+                message = self.stream.next() => {
+                    self.last_received = Instant::now();
+
+                    match message {
+                        Some(Ok(Message::Binary(data))) => self.parse_frame(&data)?,
+                        Some(Ok(Message::Close(_))) | None => {
+                            return Err(eyre!("Notifications hub closed the connection"))
+                        }
+                        // websocket pings are answered by tungstenite
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => return Err(e.into()),
+                    }
+                }
+            }
+        }
+    }
+
+    // a frame can contain several messages, each prefixed with its varint-encoded length
+    fn parse_frame(&mut self, mut data: &[u8]) -> color_eyre::Result<()> {
+        while !data.is_empty() {
+            let (length, header) = read_varint(data)?;
+            let Some(mut message) = data.get(header..header + length) else {
+                return Err(eyre!("Truncated notifications hub message"));
+            };
+            data = &data[header + length..];
+
+            self.handle_message(rmpv::decode::read_value(&mut message)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_message(&mut self, message: Value) -> color_eyre::Result<()> {
+        let Value::Array(items) = message else {
+            return Ok(());
+        };
+
+        match items.first().and_then(Value::as_u64) {
+            // [1, headers, invocation id, target, arguments]
+            Some(INVOCATION_MESSAGE) => {
+                if items.get(3).and_then(Value::as_str) != Some("ReceiveMessage") {
+                    return Ok(());
+                }
+
+                let arguments = items.get(4).and_then(Value::as_array);
+                for argument in arguments.into_iter().flatten() {
+                    if let Some(notification) = parse_notification(argument) {
+                        self.queue.push_back(notification);
+                    }
+                }
+            }
+            // [7, error, allow reconnect]
+            Some(CLOSE_MESSAGE) => {
+                return Err(match items.get(1).and_then(Value::as_str) {
+                    Some(error) => eyre!("Notifications hub closed the connection: {error}"),
+                    None => eyre!("Notifications hub closed the connection"),
+                });
+            }
+            // pings and other messages we don't care about
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+fn read_varint(data: &[u8]) -> color_eyre::Result<(usize, usize)> {
+    let mut value = 0usize;
+
+    for (i, byte) in data.iter().take(5).enumerate() {
+        value |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+
+    Err(eyre!("Invalid notifications hub message length"))
+}
+
+// servers differ in the casing of the keys
+fn get_field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    value
+        .as_map()?
+        .iter()
+        .find(|(key, _)| {
+            key.as_str()
+                .is_some_and(|key| key.eq_ignore_ascii_case(name))
+        })
+        .map(|(_, value)| value)
+}
+
+// { ContextId, Type, Payload: { Id, UserId, OrganizationId, RevisionDate, ... } }
+fn parse_notification(value: &Value) -> Option<PushNotification> {
+    let push_type = get_field(value, "Type")?
+        .as_u64()
+        .and_then(PushType::from_id)?;
+    let id = get_field(value, "Payload")
+        .and_then(|payload| get_field(payload, "Id"))
+        .and_then(Value::as_str)
+        .map(str::to_string);
+
+    Some(PushNotification { push_type, id })
+}
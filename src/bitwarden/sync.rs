@@ -10,6 +10,26 @@ use super::config::ConfigResponseModel;
 pub struct SyncResponseModel {
     #[serde(rename = "ciphers", alias = "Ciphers")]
     pub ciphers: Vec<CipherDetailsResponseModel>,
+    #[serde(rename = "profile", alias = "Profile")]
+    pub profile: Option<ProfileResponseModel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProfileResponseModel {
+    #[serde(rename = "organizations", alias = "Organizations")]
+    #[serde(default)]
+    pub organizations: Vec<ProfileOrganizationResponseModel>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProfileOrganizationResponseModel {
+    #[serde(rename = "id", alias = "Id")]
+    pub id: String,
+    #[serde(rename = "name", alias = "Name")]
+    pub name: Option<String>,
+    // organization key, encrypted with the user public key
+    #[serde(rename = "key", alias = "Key")]
+    pub key: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize_repr)]
@@ -19,6 +39,7 @@ pub enum CipherType {
     SecureNote = 2,
     Card = 3,
     Identity = 4,
+    SshKey = 5,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize)]
@@ -38,10 +59,43 @@ pub struct CipherDetailsResponseModel {
     pub notes: Option<String>,
     #[serde(rename = "secureNote", alias = "SecureNote")]
     pub secure_note: Option<CipherSecureNoteModel>,
+    #[serde(rename = "login", alias = "Login")]
+    pub login: Option<CipherLoginModel>,
+    #[serde(rename = "sshKey", alias = "SshKey")]
+    pub ssh_key: Option<CipherSshKeyModel>,
     #[serde(rename = "type", alias = "Type")]
     pub type_field: CipherType,
     #[serde(rename = "key", alias = "Key")]
     pub key: Option<String>,
+    #[serde(rename = "organizationId", alias = "OrganizationId")]
+    pub organization_id: Option<String>,
+    // "hide passwords" collection permission, already combined across the item's collections
+    #[serde(rename = "viewPassword", alias = "ViewPassword")]
+    #[serde(default = "default_true")]
+    pub view_password: bool,
+    // false if the item is only in read-only collections
+    #[serde(rename = "edit", alias = "Edit")]
+    #[serde(default = "default_true")]
+    pub edit: bool,
+    #[serde(rename = "attachments", alias = "Attachments")]
+    #[serde(default)]
+    pub attachments: Option<Vec<AttachmentResponseModel>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AttachmentResponseModel {
+    #[serde(rename = "id", alias = "Id")]
+    pub id: String,
+    #[serde(rename = "url", alias = "Url")]
+    pub url: Option<String>,
+    #[serde(rename = "fileName", alias = "FileName")]
+    pub file_name: Option<String>,
+    #[serde(rename = "key", alias = "Key")]
+    pub key: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Deserialize_repr)]
@@ -69,6 +123,24 @@ pub struct CipherSecureNoteModel {
     pub type_field: u8,
 }
 
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CipherLoginModel {
+    #[serde(rename = "username", alias = "Username")]
+    pub username: Option<String>,
+    #[serde(rename = "password", alias = "Password")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CipherSshKeyModel {
+    #[serde(rename = "privateKey", alias = "PrivateKey")]
+    pub private_key: Option<String>,
+    #[serde(rename = "publicKey", alias = "PublicKey")]
+    pub public_key: Option<String>,
+    #[serde(rename = "keyFingerprint", alias = "KeyFingerprint")]
+    pub key_fingerprint: Option<String>,
+}
+
 pub async fn bw_sync(
     client: &reqwest::Client,
     config: &ConfigResponseModel,
@@ -91,3 +163,88 @@ pub async fn bw_sync(
 
     Ok(response)
 }
+
+// returns None if the cipher doesn't exist (anymore) or isn't accessible to the user
+pub async fn bw_get_cipher(
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    token: &str,
+    cipher_id: &str,
+) -> color_eyre::Result<Option<CipherDetailsResponseModel>> {
+    let url = format!("{}/ciphers/{}/details", config.environment.api, cipher_id);
+
+    let response = client
+        .get(url)
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    let response = response.error_for_status()?.text().await?;
+
+    let mut jd = serde_json::Deserializer::from_str(&response);
+    let response = serde_path_to_error::deserialize(&mut jd)
+        .map_err(|e| eyre!("Unexpected response: {}", e))?;
+
+    Ok(Some(response))
+}
+
+// milliseconds timestamp of the last change in the account, much cheaper than a full sync
+pub async fn bw_get_revision_date(
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    token: &str,
+) -> color_eyre::Result<i64> {
+    let url = format!("{}/accounts/revision-date", config.environment.api);
+
+    let response = client
+        .get(url)
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<i64>()
+        .await?;
+
+    Ok(response)
+}
+
+pub async fn bw_download_attachment(
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    token: &str,
+    cipher_id: &str,
+    attachment_id: &str,
+) -> color_eyre::Result<Vec<u8>> {
+    // the url from the sync response might have already expired, so we request a fresh one
+    let url = format!(
+        "{}/ciphers/{}/attachment/{}",
+        config.environment.api, cipher_id, attachment_id
+    );
+
+    let attachment = client
+        .get(url)
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<AttachmentResponseModel>()
+        .await?;
+
+    let Some(url) = attachment.url else {
+        return Err(eyre!("Attachment {} has no download url", attachment_id));
+    };
+
+    let response = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+
+    Ok(response.to_vec())
+}
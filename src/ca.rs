@@ -0,0 +1,112 @@
+use color_eyre::eyre::eyre;
+use ssh_key::{
+    certificate::{Builder, CertType},
+    rand_core::{OsRng, RngCore},
+    Certificate, PrivateKey, PublicKey,
+};
+
+use crate::utils::get_current_unix_timestamp;
+
+// same as ssh-keygen defaults
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+// certificates are backdated a bit to account for clock skew between the client and the server
+const CLOCK_SKEW_SECONDS: u64 = 60;
+
+pub struct CertificateOptions {
+    pub key_id: String,
+    pub principals: Vec<String>,
+    pub validity_seconds: u64,
+    pub critical_options: Vec<(String, String)>,
+    pub extensions: Vec<(String, String)>,
+}
+
+pub fn issue_user_certificate(
+    ca_key: &PrivateKey,
+    public_key: &PublicKey,
+    options: &CertificateOptions,
+) -> color_eyre::Result<Certificate> {
+    // a certificate without principals is valid for any user, which is never what we want
+    if options.principals.is_empty() {
+        return Err(eyre!("At least one principal is required"));
+    }
+
+    let now = get_current_unix_timestamp();
+    let valid_before = now
+        .checked_add(options.validity_seconds)
+        .ok_or_else(|| eyre!("Validity period is too long"))?;
+    let mut builder = Builder::new_with_random_nonce(
+        &mut OsRng,
+        public_key.key_data().clone(),
+        now - CLOCK_SKEW_SECONDS,
+        valid_before,
+    )?;
+
+    builder
+        .serial(OsRng.next_u64())?
+        .cert_type(CertType::User)?
+        .key_id(options.key_id.clone())?
+        .comment(public_key.comment())?;
+
+    for principal in &options.principals {
+        builder.valid_principal(principal.clone())?;
+    }
+
+    for (name, value) in &options.critical_options {
+        builder.critical_option(name.clone(), value.clone())?;
+    }
+
+    for (name, value) in &options.extensions {
+        builder.extension(name.clone(), value.clone())?;
+    }
+
+    Ok(builder.sign(ca_key)?)
+}
+
+// parses durations like `30m`, `8h` or `7d`. plain numbers are treated as seconds
+pub fn parse_duration(value: &str) -> color_eyre::Result<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('s') => (&value[..value.len() - 1], 1),
+        Some('m') => (&value[..value.len() - 1], 60),
+        Some('h') => (&value[..value.len() - 1], 60 * 60),
+        Some('d') => (&value[..value.len() - 1], 24 * 60 * 60),
+        Some('w') => (&value[..value.len() - 1], 7 * 24 * 60 * 60),
+        _ => (value, 1),
+    };
+
+    let number: u64 = number
+        .parse()
+        .map_err(|_| eyre!("Invalid duration: {value}"))?;
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| eyre!("Duration is too long: {value}"))
+}
+
+// parses `name=value` pairs (or just `name` for options without a value)
+pub fn parse_option(value: &str) -> (String, String) {
+    match value.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (value.to_string(), String::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("8h").unwrap(), 8 * 60 * 60);
+        assert!(parse_duration("999999999999999999w").is_err());
+        assert!(parse_duration("-1d").is_err());
+    }
+}
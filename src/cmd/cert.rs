@@ -0,0 +1,102 @@
+use color_eyre::eyre::eyre;
+use tokio::fs;
+
+use crate::{
+    ca::{
+        issue_user_certificate, parse_duration, parse_option, CertificateOptions,
+        DEFAULT_EXTENSIONS,
+    },
+    database::Database,
+    keychain::Keychain,
+    keys::{decrypt_vault_key, get_vault_key},
+    CertCommands,
+};
+
+pub async fn cmd_cert_issue(database: Database, command: CertCommands) -> color_eyre::Result<()> {
+    let CertCommands::Issue {
+        identity,
+        ca,
+        principals,
+        validity,
+        key_id,
+        critical_options,
+        extensions,
+        output,
+    } = command;
+
+    let Some(auth) = database.get_auth()? else {
+        println!("Not logged in. Please run `bw-ssh-agent login` first.");
+        return Ok(());
+    };
+
+    let Some(identity) = database.get_identity_by_name_or_id(&identity)? else {
+        return Err(eyre!("Identity {identity} not found"));
+    };
+
+    let ca = match ca {
+        Some(ca) => database
+            .get_certificate_authority(&ca)?
+            .ok_or_else(|| eyre!("Certificate authority {ca} not found"))?,
+        None => {
+            let mut cas = database.get_certificate_authorities()?;
+            if cas.len() != 1 {
+                return Err(eyre!(
+                    "Found {} certificate authorities, please specify one with --ca",
+                    cas.len()
+                ));
+            }
+
+            cas.remove(0)
+        }
+    };
+
+    let extensions = if extensions.is_empty() {
+        DEFAULT_EXTENSIONS
+            .iter()
+            .map(|name| (name.to_string(), String::new()))
+            .collect()
+    } else {
+        extensions.iter().map(|e| parse_option(e)).collect()
+    };
+
+    let options = CertificateOptions {
+        key_id: key_id.unwrap_or_else(|| identity.name.clone()),
+        principals,
+        validity_seconds: parse_duration(&validity)?,
+        critical_options: critical_options.iter().map(|o| parse_option(o)).collect(),
+        extensions,
+    };
+
+    let organization = database.get_owning_organization(ca.organization_id.as_ref())?;
+
+    let mut keychain = Keychain::start();
+    keychain.ensure_keypair().await?;
+    let vault_key = get_vault_key(&mut keychain, &auth, organization.as_ref()).await?;
+
+    let ca_key = decrypt_vault_key(
+        &vault_key,
+        ca.intermediate_key.as_ref(),
+        &ca.private_key,
+        ca.passphrase.as_ref(),
+    )?;
+    let mut public_key = ssh_key::PublicKey::from_bytes(&identity.public_key)?;
+    public_key.set_comment(identity.name.clone());
+
+    let certificate = issue_user_certificate(&ca_key, &public_key, &options)?;
+    let certificate = certificate.to_openssh()?;
+
+    match output {
+        Some(output) => {
+            fs::write(&output, format!("{certificate}\n")).await?;
+            println!(
+                "Issued certificate for {} signed by {}, saved to {}",
+                identity.name,
+                ca.name,
+                output.to_string_lossy()
+            );
+        }
+        None => println!("{certificate}"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use color_eyre::eyre::eyre;
+
+use crate::{database::Database, ConfigCommands};
+
+pub const EXPOSE_ALL_SSH_KEYS_SETTING: &str = "expose-all-ssh-keys";
+pub const DEVICE_NAME_SETTING: &str = "device-name";
+pub const SYNC_INTERVAL_SETTING: &str = "sync-interval";
+
+// known settings and their descriptions
+const SETTINGS: &[(&str, &str)] = &[
+    (
+        EXPOSE_ALL_SSH_KEYS_SETTING,
+        "expose all SSH Key items from the vault, even without the expose field (true/false)",
+    ),
+    (
+        DEVICE_NAME_SETTING,
+        "device name shown in the vault, used on the next login (defaults to the hostname and OS)",
+    ),
+    (
+        SYNC_INTERVAL_SETTING,
+        "how often the daemon checks the vault for changes, e.g. 15m or 1h, `off` to disable background syncing entirely (defaults to 15m)",
+    ),
+];
+
+fn ensure_known(key: &str) -> color_eyre::Result<()> {
+    if SETTINGS.iter().any(|(name, _)| *name == key) {
+        return Ok(());
+    }
+
+    Err(eyre!(
+        "Unknown setting {key}, run `bw-ssh-agent config list` to see the available settings"
+    ))
+}
+
+pub fn cmd_config(database: Database, command: ConfigCommands) -> color_eyre::Result<()> {
+    match command {
+        ConfigCommands::List => {
+            for (name, description) in SETTINGS {
+                let value = database.get_setting(name)?;
+                println!(
+                    "{name} = {} ({description})",
+                    value.as_deref().unwrap_or("<unset>")
+                );
+            }
+        }
+        ConfigCommands::Get { key } => {
+            ensure_known(&key)?;
+            match database.get_setting(&key)? {
+                Some(value) => println!("{value}"),
+                None => println!("<unset>"),
+            }
+        }
+        ConfigCommands::Set { key, value } => {
+            ensure_known(&key)?;
+            database.set_setting(&key, &value)?;
+        }
+        ConfigCommands::Unset { key } => {
+            ensure_known(&key)?;
+            database.delete_setting(&key)?;
+        }
+    }
+
+    Ok(())
+}
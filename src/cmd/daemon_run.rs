@@ -4,6 +4,7 @@ use tokio::{fs, net::UnixListener};
 
 use crate::{
     agent::agent::Agent,
+    background_sync::{run_background_sync, run_certificate_renewal, run_notifications},
     cmd::utils::check_running,
     constants::{PID_PATH, SOCKET_PATH},
     database::Database,
@@ -38,9 +39,15 @@ pub async fn cmd_daemon_run(database: Database) -> color_eyre::Result<()> {
 
     keychain.ensure_keypair().await?;
 
-    Agent::new(listener)
-        .run(Arc::new(handler::Handler::new(database, keychain)))
-        .await?;
+    let agent = Agent::new(listener);
+    let handler = Arc::new(handler::Handler::new(database, keychain));
+
+    tokio::try_join!(
+        agent.run(handler.clone()),
+        run_background_sync(handler.clone()),
+        run_notifications(handler.clone()),
+        run_certificate_renewal(handler),
+    )?;
 
     Ok(())
 }
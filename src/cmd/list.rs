@@ -1,4 +1,6 @@
-use crate::database::Database;
+use std::str::FromStr;
+
+use crate::{database::Database, keys::KeyFormat};
 
 pub fn cmd_list(database: Database) -> color_eyre::Result<()> {
     let identities = database.get_identities()?;
@@ -6,7 +8,17 @@ pub fn cmd_list(database: Database) -> color_eyre::Result<()> {
     println!("{} identities:", identities.len());
     for identity in identities {
         let pub_key = ssh_key::PublicKey::from_bytes(&identity.public_key)?;
-        println!("{}: {}", identity.name, pub_key.to_openssh()?);
+        let format = KeyFormat::from_str(&identity.format)?;
+        println!("{} [{}]: {}", identity.name, format, pub_key.to_openssh()?);
+
+        if let Some(certificate) = identity.certificate {
+            let certificate = ssh_key::Certificate::from_bytes(&certificate)?;
+            println!(
+                "{} (certificate): {}",
+                identity.name,
+                certificate.to_openssh()?
+            );
+        }
     }
 
     Ok(())
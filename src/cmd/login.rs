@@ -1,28 +1,72 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    os::fd::FromRawFd,
+    time::Duration,
+};
+
+use base64::{prelude::BASE64_STANDARD, Engine};
 use color_eyre::eyre::eyre;
+use rsa::{
+    pkcs8::{EncodePrivateKey, EncodePublicKey},
+    rand_core::{OsRng, RngCore},
+};
 use rustyline::DefaultEditor;
+use zeroize::Zeroizing;
 
 use crate::{
     bitwarden::{
         auth::{
+            device::Device,
             identity::IdentityClient,
-            prelogin::{bw_prelogin, KdfType},
+            identity_dto::{
+                IdentityTokenErrorResponse, IdentityTokenSuccessResponse, PasswordLoginResponse,
+            },
+            prelogin::bw_prelogin,
+            token::TokenManager,
+            two_factor::{bw_send_email_login, TwoFactorProvider, TwoFactorRequest},
         },
-        config::bw_get_config,
+        config::{bw_get_config, ConfigResponseModel},
         constants::{get_bw_http_client, BW_DEFAULT_VAULT_URL},
-        crypto::{bw_decrypt_encstr, hkdf_expand_key, make_master_key, make_master_key_hash},
+        crypto::{
+            bw_decrypt_encstr, bw_decrypt_rsa_encstr, hkdf_expand_key, make_master_key,
+            make_master_key_hash,
+        },
+        fingerprint::fingerprint_phrase,
     },
     cmd::sync::sync_keys,
-    database::{AuthDto, Database},
+    database::{AuthDto, Database, TwoFactorRememberDto},
     keychain::Keychain,
     utils::get_current_unix_timestamp,
     Commands,
 };
 
+// auth requests expire after 15 minutes on the official server
+const AUTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+const AUTH_REQUEST_POLL_INTERVAL: Duration = Duration::from_secs(3);
+const ACCESS_CODE_ALPHABET: &[u8] = b"abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const ACCESS_CODE_LENGTH: usize = 25;
+
+// what each of the login methods ends up with
+struct LoginOutcome {
+    response: IdentityTokenSuccessResponse,
+    // not known for device logins
+    master_key: Option<[u8; 64]>,
+    symmetric_key: Vec<u8>,
+    device: Device,
+    api_key: Option<(String, String)>,
+}
+
 pub async fn cmd_login(database: Database, command: Commands) -> color_eyre::Result<()> {
     let Commands::Login {
         email,
         password,
+        password_stdin,
+        password_fd,
+        apikey,
+        device,
         vault_url,
+        allow_kdf_downgrade,
     } = command
     else {
         unreachable!()
@@ -35,7 +79,24 @@ pub async fn cmd_login(database: Database, command: Commands) -> color_eyre::Res
         .trim()
         .to_ascii_lowercase();
 
-    let password = password.unwrap_or_else(|| rl.readline("Password » ").unwrap());
+    let password = if device {
+        None
+    } else if password_stdin {
+        Some(read_password(std::io::stdin().lock())?)
+    } else if let Some(fd) = password_fd {
+        // safety: the descriptor is handed to us by the caller and not used anywhere else
+        Some(read_password(BufReader::new(unsafe {
+            File::from_raw_fd(fd)
+        }))?)
+    } else {
+        Some(password.unwrap_or_else(|| rl.readline("Password » ").unwrap()))
+    };
+
+    let api_key = if apikey {
+        Some(read_api_key(&mut rl)?)
+    } else {
+        None
+    };
 
     let vault_url = vault_url.unwrap_or(String::from(BW_DEFAULT_VAULT_URL));
     let client = get_bw_http_client();
@@ -52,49 +113,73 @@ pub async fn cmd_login(database: Database, command: Commands) -> color_eyre::Res
         );
     }
 
-    let prelogin_result = bw_prelogin(&client, &config, &email).await?;
-
-    if prelogin_result.kdf != KdfType::Pbkdf2Sha256 {
-        return Err(eyre!(
-            "KDF method {:#?} is not supported",
-            prelogin_result.kdf
-        ));
-    }
-
-    println!("Hashing password...");
-    let mut master_key = make_master_key(&password, &email, prelogin_result.kdf_iterations)?;
-    let master_key_hash = make_master_key_hash(&master_key, &password)?;
-
-    println!("Logging in...");
+    let identity = IdentityClient::new(
+        &client,
+        &config.environment.identity,
+        &config.environment.api,
+        &email,
+    );
 
-    let identity = IdentityClient::new(&client, config.environment.identity.as_str(), &email);
+    let device_info = Device::load(&database)?;
 
-    let login_result = identity.password_login(&email, &master_key_hash).await?;
+    let outcome = match password {
+        Some(password) => {
+            password_login(
+                &mut rl,
+                &database,
+                &client,
+                &config,
+                &identity,
+                &email,
+                &password,
+                device_info,
+                api_key,
+                allow_kdf_downgrade,
+            )
+            .await?
+        }
+        None => device_login(&identity, &email, device_info, AUTH_REQUEST_POLL_INTERVAL).await?,
+    };
 
-    if master_key.len() == 32 {
-        master_key = hkdf_expand_key(&master_key)?.to_vec();
-    }
+    let LoginOutcome {
+        response: login_result,
+        master_key,
+        symmetric_key,
+        device: device_info,
+        api_key,
+    } = outcome;
 
-    let master_key: [u8; 64] = master_key
-        .try_into()
-        .map_err(|_| eyre!("Invalid master key length"))?;
-
-    let symmetric_key = bw_decrypt_encstr(&master_key, &login_result.key)?;
+    // needed to decrypt organization keys
+    let private_key = Zeroizing::new(bw_decrypt_encstr(
+        &symmetric_key,
+        &login_result.private_key,
+    )?);
 
     let mut keychain = Keychain::start();
     keychain.ensure_keypair().await?;
 
-    let encrypted_master_key = keychain.encrypt_data(master_key.to_vec()).await?;
+    let encrypted_master_key = match master_key {
+        Some(master_key) => keychain.encrypt_data(master_key.to_vec()).await?,
+        // the master key is only kept for reference and never read back
+        None => vec![],
+    };
     let encrypted_symmetric_key = keychain.encrypt_data(symmetric_key.to_vec()).await?;
+    let encrypted_private_key = keychain.encrypt_data(private_key.to_vec()).await?;
 
     let auth = AuthDto {
         vault_url: config.environment.vault.clone(),
         access_token: login_result.access_token,
-        refresh_token: login_result.refresh_token,
+        // api key logins are renewed using the api key instead
+        refresh_token: login_result.refresh_token.unwrap_or_default(),
         expires_at: get_current_unix_timestamp() + login_result.expires_in,
         master_key: encrypted_master_key,
         symmetric_key: encrypted_symmetric_key,
         email: email.to_string(),
+        private_key: Some(encrypted_private_key),
+        client_id: api_key.as_ref().map(|(client_id, _)| client_id.clone()),
+        client_secret: api_key.map(|(_, client_secret)| client_secret),
+        device_identifier: Some(device_info.identifier),
+        revision_date: None,
     };
 
     database.set_auth(&auth)?;
@@ -102,7 +187,529 @@ pub async fn cmd_login(database: Database, command: Commands) -> color_eyre::Res
     println!("Logged in successfully!");
 
     println!("Syncing keys...");
-    sync_keys(&database, &client, &config, &symmetric_key, &auth).await?;
+    let mut token_manager = TokenManager::new(&database, &identity, &auth);
+    sync_keys(
+        &database,
+        &client,
+        &config,
+        &symmetric_key,
+        Some(&private_key),
+        &mut token_manager,
+    )
+    .await?;
 
     Ok(())
 }
+
+#[allow(clippy::too_many_arguments)]
+async fn password_login(
+    rl: &mut DefaultEditor,
+    database: &Database,
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    identity: &IdentityClient<'_>,
+    email: &str,
+    password: &str,
+    device: Device,
+    api_key: Option<(String, String)>,
+    allow_kdf_downgrade: bool,
+) -> color_eyre::Result<LoginOutcome> {
+    let prelogin_result = bw_prelogin(client, config, email).await?;
+
+    let kdf = prelogin_result.kdf_params()?;
+    kdf.check_bounds()?;
+
+    if let Some(known_kdf) = database.get_kdf_params(&config.environment.vault, email)? {
+        if kdf.is_weaker_than(&known_kdf) && !allow_kdf_downgrade {
+            return Err(eyre!(
+                "Server returned weaker KDF parameters than before ({kdf}, previously {known_kdf}). \
+                This might mean that the server is trying to obtain an easy to crack hash of your password. \
+                If you have changed the KDF settings yourself, run the login again with --allow-kdf-downgrade"
+            ));
+        }
+    }
+
+    println!("Hashing password...");
+    let mut master_key = make_master_key(password, email, &kdf)?;
+    let master_key_hash = make_master_key_hash(&master_key, password)?;
+
+    println!("Logging in...");
+
+    // remember tokens are only accepted together with the device identifier they were issued for
+    let remembered = match api_key {
+        Some(_) => None,
+        None => database
+            .get_two_factor_remember(email)?
+            .filter(|remembered| remembered.device_identifier == device.identifier),
+    };
+    let mut two_factor = remembered.map(|remembered| TwoFactorRequest {
+        provider: TwoFactorProvider::Remember,
+        token: remembered.token,
+        remember: false,
+    });
+    let mut new_device_otp = None;
+
+    let login_result = if let Some((ref client_id, ref client_secret)) = api_key {
+        identity
+            .api_key_login(client_id, client_secret, &device)
+            .await?
+    } else {
+        loop {
+            let response = identity
+                .password_login(
+                    email,
+                    &master_key_hash,
+                    &device,
+                    two_factor.as_ref(),
+                    new_device_otp.as_deref(),
+                )
+                .await?;
+
+            match response {
+                PasswordLoginResponse::Success(result) => break *result,
+                PasswordLoginResponse::NewDeviceVerificationRequired => {
+                    println!(
+                        "This device needs to be verified, a code has been sent to your email"
+                    );
+                    let otp = rl.readline("Verification code » ")?;
+                    new_device_otp = Some(otp.trim().to_string());
+                }
+                PasswordLoginResponse::TwoFactorRequired(challenge) => {
+                    two_factor = Some(
+                        prompt_two_factor(
+                            rl,
+                            client,
+                            config,
+                            email,
+                            &master_key_hash,
+                            &device.identifier,
+                            &challenge,
+                        )
+                        .await?,
+                    );
+                }
+            }
+        }
+    };
+
+    if let Some(ref token) = login_result.two_factor_token {
+        database.set_two_factor_remember(&TwoFactorRememberDto {
+            email: email.to_string(),
+            device_identifier: device.identifier.clone(),
+            token: token.clone(),
+        })?;
+    }
+
+    if master_key.len() == 32 {
+        master_key = hkdf_expand_key(&master_key)?.to_vec();
+    }
+
+    let master_key: [u8; 64] = master_key
+        .try_into()
+        .map_err(|_| eyre!("Invalid master key length"))?;
+
+    let symmetric_key = bw_decrypt_encstr(&master_key, &login_result.key)?;
+
+    // only remember the parameters once they turned out to decrypt the vault key
+    database.set_kdf_params(&config.environment.vault, email, &kdf)?;
+
+    Ok(LoginOutcome {
+        response: login_result,
+        master_key: Some(master_key),
+        symmetric_key,
+        device,
+        api_key,
+    })
+}
+
+// "log in with device": another logged in client approves the request
+// and sends us the user key encrypted with an ephemeral rsa key
+async fn device_login(
+    identity: &IdentityClient<'_>,
+    email: &str,
+    device: Device,
+    poll_interval: Duration,
+) -> color_eyre::Result<LoginOutcome> {
+    let private_key = rsa::RsaPrivateKey::new(&mut OsRng, 2048)?;
+    let public_key = private_key.to_public_key().to_public_key_der()?;
+    let private_key = private_key.to_pkcs8_der()?;
+
+    let fingerprint = fingerprint_phrase(email, public_key.as_bytes())?;
+
+    let access_code = (0..ACCESS_CODE_LENGTH)
+        .map(|_| {
+            let index = OsRng.next_u32() as usize % ACCESS_CODE_ALPHABET.len();
+            ACCESS_CODE_ALPHABET[index] as char
+        })
+        .collect::<String>();
+
+    let request = identity
+        .create_auth_request(
+            &BASE64_STANDARD.encode(public_key.as_bytes()),
+            &device.identifier,
+            &access_code,
+        )
+        .await?;
+
+    println!("Login request sent, approve it in one of the Bitwarden apps you are logged into.");
+    println!("Make sure the app shows the same fingerprint phrase: {fingerprint}");
+    println!("Waiting for approval...");
+
+    let deadline = tokio::time::Instant::now() + AUTH_REQUEST_TIMEOUT;
+    let response = loop {
+        if tokio::time::Instant::now() > deadline {
+            return Err(eyre!("Login request has expired"));
+        }
+
+        tokio::time::sleep(poll_interval).await;
+
+        let response = match identity
+            .get_auth_request_response(&request.id, &access_code)
+            .await
+        {
+            Ok(response) => response,
+            // e.g. the network changed while waiting, no reason to make the user start over
+            Err(e) if is_transient_error(&e) => {
+                eprintln!("Error checking the login request, retrying: {e}");
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        match response.request_approved {
+            Some(true) => break response,
+            Some(false) => return Err(eyre!("Login request was denied")),
+            None => {}
+        }
+    };
+
+    let Some(ref key) = response.key else {
+        return Err(eyre!("Approved login request does not contain a key"));
+    };
+
+    let login_result = match identity
+        .auth_request_login(email, &access_code, &request.id, &device)
+        .await?
+    {
+        PasswordLoginResponse::Success(result) => *result,
+        PasswordLoginResponse::TwoFactorRequired(_)
+        | PasswordLoginResponse::NewDeviceVerificationRequired => {
+            return Err(eyre!(
+                "Additional verification is required, please log in using your master password instead"
+            ));
+        }
+    };
+
+    let key = Zeroizing::new(bw_decrypt_rsa_encstr(private_key.as_bytes(), key)?);
+
+    // older clients send the master key along with its hash instead of the user key
+    let (master_key, symmetric_key) = if response.master_password_hash.is_some() {
+        let master_key = match key.len() {
+            32 => hkdf_expand_key(&key)?,
+            _ => key
+                .as_slice()
+                .try_into()
+                .map_err(|_| eyre!("Invalid master key length"))?,
+        };
+        let symmetric_key = bw_decrypt_encstr(&master_key, &login_result.key)?;
+
+        (Some(master_key), symmetric_key)
+    } else {
+        (None, key.to_vec())
+    };
+
+    Ok(LoginOutcome {
+        response: login_result,
+        master_key,
+        symmetric_key,
+        device,
+        api_key: None,
+    })
+}
+
+fn is_transient_error(error: &color_eyre::Report) -> bool {
+    let Some(error) = error.downcast_ref::<reqwest::Error>() else {
+        return false;
+    };
+
+    match error.status() {
+        Some(status) => {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        }
+        None => error.is_connect() || error.is_timeout() || error.is_request() || error.is_body(),
+    }
+}
+
+async fn prompt_two_factor(
+    rl: &mut DefaultEditor,
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    email: &str,
+    master_key_hash: &str,
+    device_identifier: &str,
+    challenge: &IdentityTokenErrorResponse,
+) -> color_eyre::Result<TwoFactorRequest> {
+    let providers = challenge.two_factor_providers.as_ref().unwrap();
+
+    let mut available = providers
+        .keys()
+        .filter_map(|id| TwoFactorProvider::from_id(id))
+        .filter(|provider| *provider != TwoFactorProvider::Remember)
+        .collect::<Vec<_>>();
+    available.sort_by_key(|provider| *provider as u8);
+
+    let supported = available
+        .iter()
+        .copied()
+        .filter(TwoFactorProvider::is_supported)
+        .collect::<Vec<_>>();
+
+    if supported.is_empty() {
+        let names = available
+            .iter()
+            .map(|provider| provider.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        return Err(eyre!(
+            "Two-step login is required, but none of the enabled methods ({names}) are supported. \
+            Please enable an authenticator app, email or YubiKey OTP"
+        ));
+    }
+
+    let provider = if supported.len() == 1 {
+        supported[0]
+    } else {
+        println!("Two-step login is required. Available methods:");
+        for (i, provider) in supported.iter().enumerate() {
+            println!("  {}. {provider}", i + 1);
+        }
+
+        loop {
+            let choice = rl.readline("Method » ")?;
+            match choice.trim().parse::<usize>() {
+                Ok(n) if (1..=supported.len()).contains(&n) => break supported[n - 1],
+                _ => println!("Please enter a number from 1 to {}", supported.len()),
+            }
+        }
+    };
+
+    if provider == TwoFactorProvider::Email {
+        // the server sends the email by itself when it's the only method, same as the official clients
+        if available.len() > 1 {
+            bw_send_email_login(
+                client,
+                config,
+                email,
+                master_key_hash,
+                device_identifier,
+                challenge.sso_email_2fa_session_token.as_deref(),
+            )
+            .await?;
+        }
+
+        let address = providers
+            .get(&(TwoFactorProvider::Email as u8).to_string())
+            .and_then(|data| data.as_ref())
+            .and_then(|data| data.get("Email").or_else(|| data.get("email")))
+            .and_then(|address| address.as_str());
+        match address {
+            Some(address) => println!("A verification code has been sent to {address}"),
+            None => println!("A verification code has been sent to your email"),
+        }
+    }
+
+    let token = rl.readline(&format!("{provider} code » "))?;
+
+    Ok(TwoFactorRequest {
+        provider,
+        token: token.trim().to_string(),
+        remember: true,
+    })
+}
+
+// reads the first line, so that `echo $PASSWORD |` works
+fn read_password(mut reader: impl BufRead) -> color_eyre::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(eyre!("No password was provided"));
+    }
+
+    Ok(password.to_string())
+}
+
+// same environment variables as the official cli
+fn read_api_key(rl: &mut DefaultEditor) -> color_eyre::Result<(String, String)> {
+    let client_id = match std::env::var("BW_CLIENTID") {
+        Ok(client_id) => client_id,
+        Err(_) => rl.readline("client_id » ")?,
+    };
+    let client_secret = match std::env::var("BW_CLIENTSECRET") {
+        Ok(client_secret) => client_secret,
+        Err(_) => rl.readline("client_secret » ")?,
+    };
+
+    let client_id = client_id.trim();
+    if !client_id.starts_with("user.") {
+        return Err(eyre!(
+            "Only personal API keys (client_id starting with \"user.\") can be used to log in"
+        ));
+    }
+
+    Ok((client_id.to_string(), client_secret.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use rsa::{pkcs8::DecodePublicKey, Oaep, RsaPublicKey};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const EMAIL: &str = "me@example.com";
+    const REQUEST_ID: &str = "b3b0c1fe-5f84-4b39-9c7d-0a6d2b3e5e11";
+
+    #[derive(Default)]
+    struct MockState {
+        public_key: Option<String>,
+        access_code: Option<String>,
+        polls: u32,
+    }
+
+    // answers like the bitwarden server would, the request is approved on the second poll
+    fn respond(state: &Mutex<MockState>, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut state = state.lock().unwrap();
+        let user_key = [7u8; 64];
+
+        match (method, path.split_once('?').map_or(path, |(path, _)| path)) {
+            ("POST", "/api/auth-requests") => {
+                let request: serde_json::Value = serde_json::from_str(body).unwrap();
+                assert_eq!(request["email"], EMAIL);
+                assert_eq!(request["type"], 0);
+                state.public_key = request["publicKey"].as_str().map(str::to_string);
+                state.access_code = request["accessCode"].as_str().map(str::to_string);
+
+                (
+                    200,
+                    format!(r#"{{"id":"{REQUEST_ID}","requestApproved":null}}"#),
+                )
+            }
+            ("GET", p) if p == format!("/api/auth-requests/{REQUEST_ID}/response") => {
+                let code = format!("code={}", state.access_code.as_deref().unwrap());
+                assert!(path.ends_with(&code));
+
+                state.polls += 1;
+                if state.polls < 2 {
+                    return (
+                        200,
+                        format!(r#"{{"id":"{REQUEST_ID}","requestApproved":null}}"#),
+                    );
+                }
+
+                let public_key = BASE64_STANDARD
+                    .decode(state.public_key.as_deref().unwrap())
+                    .unwrap();
+                let public_key = RsaPublicKey::from_public_key_der(&public_key).unwrap();
+                let key = public_key
+                    .encrypt(&mut OsRng, Oaep::new::<sha1::Sha1>(), &user_key)
+                    .unwrap();
+
+                (
+                    200,
+                    format!(
+                        r#"{{"id":"{REQUEST_ID}","key":"4.{}","masterPasswordHash":null,"requestApproved":true}}"#,
+                        BASE64_STANDARD.encode(key)
+                    ),
+                )
+            }
+            ("POST", "/identity/connect/token") => {
+                let form: std::collections::HashMap<String, String> =
+                    serde_urlencoded::from_str(body).unwrap();
+                assert_eq!(form["grant_type"], "password");
+                assert_eq!(form["username"], EMAIL);
+                assert_eq!(&form["password"], state.access_code.as_ref().unwrap());
+                assert_eq!(form["authRequest"], REQUEST_ID);
+
+                (
+                    200,
+                    r#"{"Kdf":0,"KdfIterations":600000,"Key":"2.key","PrivateKey":"2.private",
+                        "ResetMasterPassword":false,"access_token":"access","refresh_token":"refresh",
+                        "expires_in":3600,"scope":"api offline_access","token_type":"Bearer"}"#
+                        .to_string(),
+                )
+            }
+            _ => (404, String::new()),
+        }
+    }
+
+    async fn serve(listener: TcpListener, state: Arc<Mutex<MockState>>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+
+            let mut request_line = String::new();
+            stream.read_line(&mut request_line).await.unwrap();
+            let mut parts = request_line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                stream.read_line(&mut header).await.unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0u8; content_length];
+            stream.read_exact(&mut body).await.unwrap();
+
+            let (status, body) = respond(&state, method, path, &String::from_utf8(body).unwrap());
+            let response = format!(
+                "HTTP/1.1 {status} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn device_login_against_mock_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+        tokio::spawn(serve(listener, state.clone()));
+
+        let client = reqwest::Client::new();
+        let identity_base = format!("{base}/identity");
+        let api_base = format!("{base}/api");
+        let identity = IdentityClient::new(&client, &identity_base, &api_base, EMAIL);
+        let device = Device {
+            identifier: String::from("device"),
+            name: String::from("test"),
+        };
+
+        let outcome = device_login(&identity, EMAIL, device, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        assert_eq!(state.lock().unwrap().polls, 2);
+        assert_eq!(outcome.symmetric_key, [7u8; 64]);
+        assert!(outcome.master_key.is_none());
+        assert_eq!(outcome.response.access_token, "access");
+    }
+}
@@ -1,3 +1,5 @@
+pub mod cert;
+pub mod config;
 pub mod daemon_register;
 pub mod daemon_run;
 pub mod list;
@@ -1,70 +1,568 @@
 use core::str;
-use std::str::FromStr;
+use std::collections::HashMap;
+
+use ssh_key::{Certificate, HashAlg, PrivateKey};
+use zeroize::Zeroizing;
 
 use crate::{
     bitwarden::{
         auth::{identity::IdentityClient, token::TokenManager},
         config::{bw_get_config, ConfigResponseModel},
         constants::get_bw_http_client,
-        crypto::bw_decrypt_encstr,
-        sync::{bw_sync, CipherDetailsResponseModel, CipherType},
+        crypto::{bw_decrypt_encbuf, bw_decrypt_encstr, bw_decrypt_rsa_encstr},
+        sync::{
+            bw_download_attachment, bw_get_revision_date, bw_sync, CipherDetailsResponseModel,
+            CipherType, ProfileOrganizationResponseModel,
+        },
     },
-    database::{AuthDto, Database, IdentityDto},
+    cmd::config::EXPOSE_ALL_SSH_KEYS_SETTING,
+    database::{CertificateAuthorityDto, Database, IdentityDto, OrganizationDto},
+    destination::parse_destinations,
     keychain::Keychain,
+    keys::{decode_private_key, DecodedKey},
+    utils::get_current_unix_timestamp,
 };
 
 const BW_EXPOSE_FIELD: &str = "desu.tei.bw-ssh-agent:expose";
+const BW_ALLOWED_HOSTS_FIELD: &str = "desu.tei.bw-ssh-agent:allowed-hosts";
+const BW_ALLOW_FORWARDING_FIELD: &str = "desu.tei.bw-ssh-agent:allow-forwarding";
+const BW_CERTIFICATE_FIELD: &str = "desu.tei.bw-ssh-agent:certificate";
+const BW_CERTIFICATE_AUTHORITY_FIELD: &str = "desu.tei.bw-ssh-agent:certificate-authority";
+const BW_CA_FIELD: &str = "desu.tei.bw-ssh-agent:ca";
+const BW_PRINCIPALS_FIELD: &str = "desu.tei.bw-ssh-agent:principals";
+const BW_ALLOW_RSA_SHA1_FIELD: &str = "desu.tei.bw-ssh-agent:allow-rsa-sha1";
+const BW_PASSPHRASE_FIELD: &str = "desu.tei.bw-ssh-agent:passphrase";
+const BW_ALLOW_HIDDEN_FIELD: &str = "desu.tei.bw-ssh-agent:allow-hidden";
+const CERTIFICATE_ATTACHMENT_SUFFIX: &str = "-cert.pub";
+
+struct ExtractedKey<'a> {
+    cipher_key: Zeroizing<Vec<u8>>,
+    expose: bool,
+    is_ca: bool,
+    name: String,
+    private_key: String,
+    encrypted_private_key: &'a String,
+    allowed_destinations: Vec<String>,
+    allow_forwarding: bool,
+    certificate: Option<String>,
+    ca: Option<String>,
+    principals: Vec<String>,
+    allow_rsa_sha1: bool,
+    encrypted_passphrase: Option<&'a String>,
+    // fingerprint reported by the server for ssh key items
+    fingerprint: Option<String>,
+    allow_hidden: bool,
+}
 
 fn extract_key_from_cipher<'a>(
     cipher: &'a CipherDetailsResponseModel,
     symmetric_key: &[u8],
-) -> color_eyre::Result<Option<(String, String, &'a String)>> {
-    let Some(ref fields) = cipher.fields else {
-        return Ok(None);
-    };
+    expose_all_ssh_keys: bool,
+) -> color_eyre::Result<Option<ExtractedKey<'a>>> {
+    let is_ssh_key_item = cipher.type_field == CipherType::SshKey;
 
-    let cipher_key = if let Some(ref encrypted) = cipher.key {
+    let cipher_key = Zeroizing::new(if let Some(ref encrypted) = cipher.key {
         bw_decrypt_encstr(symmetric_key, encrypted)?
     } else {
         symmetric_key.to_vec()
-    };
+    });
 
     let mut expose = false;
-    for field in fields {
-        if let Some(field_name) = &field.name {
-            if bw_decrypt_encstr(&cipher_key, &field_name)? != BW_EXPOSE_FIELD.as_bytes() {
-                continue;
-            }
+    let mut allowed_destinations = vec![];
+    let mut allow_forwarding = false;
+    let mut certificate = None;
+    let mut is_ca = false;
+    let mut ca = None;
+    let mut principals = vec![];
+    let mut allow_rsa_sha1 = false;
+    let mut encrypted_passphrase = None;
+    let mut allow_hidden = false;
+    for field in cipher.fields.iter().flatten() {
+        let field_name = if let Some(field_name) = &field.name {
+            bw_decrypt_encstr(&cipher_key, field_name)?
         } else {
             continue;
-        }
+        };
 
-        let value = if let Some(value) = &field.value {
-            bw_decrypt_encstr(&cipher_key, value)?
-        } else {
+        let Some(encrypted_value) = &field.value else {
             continue;
         };
+        let value = bw_decrypt_encstr(&cipher_key, encrypted_value)?;
 
-        if value == b"1" || value == b"true" {
-            expose = true;
+        match str::from_utf8(&field_name) {
+            Ok(BW_EXPOSE_FIELD) if value == b"1" || value == b"true" => {
+                expose = true;
+            }
+            Ok(BW_ALLOW_FORWARDING_FIELD) if value == b"1" || value == b"true" => {
+                allow_forwarding = true;
+            }
+            Ok(BW_ALLOWED_HOSTS_FIELD) => {
+                allowed_destinations = parse_destinations(str::from_utf8(&value)?);
+            }
+            Ok(BW_CERTIFICATE_FIELD) => {
+                certificate = Some(String::from_utf8(value)?);
+            }
+            Ok(BW_ALLOW_RSA_SHA1_FIELD) if value == b"1" || value == b"true" => {
+                allow_rsa_sha1 = true;
+            }
+            Ok(BW_CERTIFICATE_AUTHORITY_FIELD) if value == b"1" || value == b"true" => {
+                is_ca = true;
+            }
+            Ok(BW_CA_FIELD) => {
+                ca = Some(String::from_utf8(value)?.trim().to_string());
+            }
+            Ok(BW_PRINCIPALS_FIELD) => {
+                principals = parse_destinations(str::from_utf8(&value)?);
+            }
+            Ok(BW_ALLOW_HIDDEN_FIELD) if value == b"1" || value == b"true" => {
+                allow_hidden = true;
+            }
+            Ok(BW_PASSPHRASE_FIELD) => {
+                // kept encrypted, it is only needed at sign time
+                encrypted_passphrase = Some(encrypted_value);
+            }
+            _ => {}
         }
     }
 
-    if !expose {
+    // fall back to the login password for login items
+    let encrypted_passphrase = encrypted_passphrase.or_else(|| {
+        cipher
+            .login
+            .as_ref()
+            .and_then(|login| login.password.as_ref())
+    });
+
+    if is_ssh_key_item && expose_all_ssh_keys {
+        expose = true;
+    }
+
+    if !expose && !is_ca {
         return Ok(None);
     }
 
-    let Some(ref encrypted_private_key) = cipher.notes else {
+    let (encrypted_private_key, encrypted_fingerprint) = match cipher.ssh_key {
+        Some(ref ssh_key) if is_ssh_key_item => (
+            ssh_key.private_key.as_ref(),
+            ssh_key.key_fingerprint.as_ref(),
+        ),
+        _ => (cipher.notes.as_ref(), None),
+    };
+
+    let Some(encrypted_private_key) = encrypted_private_key else {
         return Ok(None);
     };
 
+    let fingerprint = match encrypted_fingerprint {
+        Some(fingerprint) => Some(String::from_utf8(bw_decrypt_encstr(
+            &cipher_key,
+            fingerprint,
+        )?)?),
+        None => None,
+    };
+
     let private_key = String::from_utf8(bw_decrypt_encstr(&cipher_key, encrypted_private_key)?)?;
     let name = String::from_utf8(bw_decrypt_encstr(
         &cipher_key,
         &cipher.name.as_ref().unwrap(),
     )?)?;
 
-    Ok(Some((name, private_key, encrypted_private_key)))
+    Ok(Some(ExtractedKey {
+        cipher_key,
+        expose,
+        is_ca,
+        name,
+        private_key,
+        encrypted_private_key,
+        allowed_destinations,
+        allow_forwarding,
+        certificate,
+        ca,
+        principals,
+        allow_rsa_sha1,
+        encrypted_passphrase,
+        fingerprint,
+        allow_hidden,
+    }))
+}
+
+async fn fetch_certificate_attachment(
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    access_token: &str,
+    cipher: &CipherDetailsResponseModel,
+    cipher_key: &[u8],
+) -> color_eyre::Result<Option<String>> {
+    let Some(ref attachments) = cipher.attachments else {
+        return Ok(None);
+    };
+
+    for attachment in attachments {
+        let Some(ref file_name) = attachment.file_name else {
+            continue;
+        };
+
+        let file_name = String::from_utf8(bw_decrypt_encstr(cipher_key, file_name)?)?;
+        if !file_name.ends_with(CERTIFICATE_ATTACHMENT_SUFFIX) {
+            continue;
+        }
+
+        // older attachments are encrypted with the cipher key directly
+        let attachment_key = Zeroizing::new(if let Some(ref key) = attachment.key {
+            bw_decrypt_encstr(cipher_key, key)?
+        } else {
+            cipher_key.to_vec()
+        });
+
+        let data = bw_download_attachment(client, config, access_token, &cipher.id, &attachment.id)
+            .await?;
+
+        return Ok(Some(String::from_utf8(bw_decrypt_encbuf(
+            &attachment_key,
+            &data,
+        )?)?));
+    }
+
+    Ok(None)
+}
+
+fn parse_certificate(
+    name: &str,
+    certificate: &str,
+    ssh_key: &PrivateKey,
+) -> color_eyre::Result<Option<Vec<u8>>> {
+    let certificate = Certificate::from_openssh(certificate.trim())?;
+
+    if certificate.public_key() != ssh_key.public_key().key_data() {
+        println!(
+            "Certificate for \"{}\" does not match its private key",
+            name
+        );
+        return Ok(None);
+    }
+
+    if certificate.valid_before() < get_current_unix_timestamp() {
+        println!("Warning: certificate for \"{}\" has expired", name);
+    }
+
+    Ok(Some(certificate.to_bytes()?))
+}
+
+// stores the organization keys for sign time, and decrypts them for use during the sync
+fn sync_organizations(
+    database: &Database,
+    organizations: &[ProfileOrganizationResponseModel],
+    private_key: Option<&[u8]>,
+) -> color_eyre::Result<HashMap<String, Zeroizing<Vec<u8>>>> {
+    let mut keys = HashMap::new();
+
+    for organization in organizations {
+        let Some(ref key) = organization.key else {
+            continue;
+        };
+        let name = organization.name.clone().unwrap_or_default();
+
+        database.add_organization(&OrganizationDto {
+            id: organization.id.clone(),
+            name: name.clone(),
+            key: key.clone(),
+        })?;
+
+        let Some(private_key) = private_key else {
+            continue;
+        };
+
+        match bw_decrypt_rsa_encstr(private_key, key) {
+            Ok(organization_key) => {
+                keys.insert(organization.id.clone(), Zeroizing::new(organization_key));
+            }
+            Err(e) => println!("Error decrypting the key of organization {}: {:?}", name, e),
+        }
+    }
+
+    for old in database.get_organizations()? {
+        if !organizations.iter().any(|o| o.id == old.id) {
+            database.delete_organization(&old.id)?;
+        }
+    }
+
+    Ok(keys)
+}
+
+// keys live either in ssh key items, or in the notes of a secure note or a login
+// (whose password can be the passphrase)
+pub fn is_key_cipher(cipher: &CipherDetailsResponseModel) -> bool {
+    let is_note = cipher.type_field == CipherType::SecureNote && cipher.secure_note.is_some();
+    let is_login = cipher.type_field == CipherType::Login && cipher.login.is_some();
+    let is_ssh_key = cipher.type_field == CipherType::SshKey && cipher.ssh_key.is_some();
+
+    (is_note || is_login || is_ssh_key) && cipher.deleted_date.is_none()
+}
+
+struct SyncContext<'a> {
+    database: &'a Database,
+    client: &'a reqwest::Client,
+    config: &'a ConfigResponseModel,
+    access_token: &'a str,
+    expose_all_ssh_keys: bool,
+    // state before the sync
+    identities: Vec<IdentityDto>,
+    cas: Vec<CertificateAuthorityDto>,
+}
+
+#[derive(Default)]
+struct SyncSummary {
+    found: usize,
+    changed: usize,
+    new_identities: Vec<String>,
+    new_cas: Vec<String>,
+    skipped: Vec<String>,
+    hidden: Vec<String>,
+}
+
+impl SyncSummary {
+    fn print_skipped(&self) {
+        if !self.hidden.is_empty() {
+            println!(
+                "Skipped {} keys with hidden contents (\"hide passwords\" collection permission): {}",
+                self.hidden.len(),
+                self.hidden.join(", ")
+            );
+        }
+
+        if !self.skipped.is_empty() {
+            println!(
+                "Skipped {} keys that could not be decoded or verified: {}",
+                self.skipped.len(),
+                self.skipped.join(", ")
+            );
+        }
+    }
+}
+
+async fn sync_cipher(
+    ctx: &SyncContext<'_>,
+    summary: &mut SyncSummary,
+    cipher: &CipherDetailsResponseModel,
+    vault_key: &[u8],
+) -> color_eyre::Result<()> {
+    let ExtractedKey {
+        cipher_key,
+        expose,
+        is_ca,
+        name,
+        private_key,
+        encrypted_private_key,
+        allowed_destinations,
+        allow_forwarding,
+        certificate,
+        ca,
+        principals,
+        allow_rsa_sha1,
+        encrypted_passphrase,
+        fingerprint,
+        allow_hidden,
+    } = {
+        match extract_key_from_cipher(cipher, vault_key, ctx.expose_all_ssh_keys) {
+            Ok(Some(keys)) => keys,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                println!("Error extracting key from cipher id {}: {:?}", cipher.id, e);
+                return Ok(());
+            }
+        }
+    };
+
+    let passphrase = match encrypted_passphrase
+        .map(|passphrase| bw_decrypt_encstr(&cipher_key, passphrase))
+        .transpose()
+    {
+        Ok(passphrase) => passphrase.map(Zeroizing::new),
+        Err(e) => {
+            println!("Error decrypting passphrase for \"{}\": {:?}", name, e);
+            return Ok(());
+        }
+    };
+
+    // users who aren't allowed to see the item contents shouldn't be able to use the key either,
+    // unless someone who can edit the item (i.e. not this user) explicitly allowed it
+    let consented = allow_hidden && !cipher.edit;
+    if !cipher.view_password && !consented {
+        summary.hidden.push(name);
+        return Ok(());
+    }
+
+    // this also makes sure the passphrase works, so that we don't only find out at sign time
+    let DecodedKey {
+        private_key: ssh_key,
+        format,
+        encrypted,
+    } = match decode_private_key(&private_key, passphrase.as_deref().map(Vec::as_slice)) {
+        Ok(key) => key,
+        Err(e) => {
+            println!(
+                "Error decoding SSH key from the note named \"{}\": {}",
+                name, e
+            );
+            summary.skipped.push(name);
+            return Ok(());
+        }
+    };
+    let pub_key = ssh_key.public_key().to_bytes()?;
+
+    // the server-provided fingerprint is just metadata, make sure it actually matches the key
+    if let Some(fingerprint) = fingerprint {
+        let local_fingerprint = ssh_key.public_key().fingerprint(HashAlg::Sha256);
+
+        if fingerprint.trim() != local_fingerprint.to_string() {
+            println!(
+                "Fingerprint of \"{}\" does not match its private key (vault: {}, actual: {})",
+                name,
+                fingerprint.trim(),
+                local_fingerprint
+            );
+            summary.skipped.push(name);
+            return Ok(());
+        }
+    }
+
+    let passphrase = if encrypted {
+        encrypted_passphrase.cloned()
+    } else {
+        None
+    };
+
+    if is_ca {
+        let should_update = match ctx.cas.iter().find(|c| c.id == cipher.id) {
+            Some(old) => {
+                old.name != name
+                    || old.public_key != pub_key
+                    || old.passphrase != passphrase
+                    || old.organization_id != cipher.organization_id
+            }
+            None => true,
+        };
+
+        if should_update {
+            println!("Updating certificate authority {}", name);
+
+            ctx.database
+                .add_certificate_authority(&CertificateAuthorityDto {
+                    id: cipher.id.clone(),
+                    name: name.clone(),
+                    public_key: pub_key.clone(),
+                    private_key: encrypted_private_key.clone(),
+                    intermediate_key: cipher.key.clone(),
+                    passphrase: passphrase.clone(),
+                    organization_id: cipher.organization_id.clone(),
+                })?;
+            summary.changed += 1;
+        }
+
+        summary.new_cas.push(cipher.id.clone());
+    }
+
+    if !expose {
+        return Ok(());
+    }
+
+    // a certificate without principals would be refused on every renewal, so say it once here
+    let ca = match ca {
+        Some(_) if principals.is_empty() => {
+            println!(
+                "Not issuing certificates for \"{}\": the {} field is empty",
+                name, BW_PRINCIPALS_FIELD
+            );
+            None
+        }
+        ca => ca,
+    };
+
+    let certificate = match certificate {
+        Some(certificate) => Ok(Some(certificate)),
+        None => {
+            fetch_certificate_attachment(
+                ctx.client,
+                ctx.config,
+                ctx.access_token,
+                cipher,
+                &cipher_key,
+            )
+            .await
+        }
+    };
+
+    let old = ctx.identities.iter().find(|i| i.id == cipher.id);
+
+    // a failed download shouldn't take away a certificate that is still in the vault
+    let previous_certificate = || {
+        old.filter(|old| old.public_key == pub_key)
+            .and_then(|old| old.certificate.clone())
+    };
+
+    let certificate = match certificate {
+        Ok(Some(certificate)) => match parse_certificate(&name, &certificate, &ssh_key) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                println!("Error parsing certificate for \"{}\": {}", name, e);
+                previous_certificate()
+            }
+        },
+        Ok(None) => None,
+        Err(e) => {
+            println!("Error fetching certificate for \"{}\": {:?}", name, e);
+            previous_certificate()
+        }
+    };
+
+    summary.found += 1;
+
+    let mut should_update = false;
+
+    if let Some(old) = old {
+        if old.name != name
+            || old.public_key != pub_key
+            || old.allowed_destinations != allowed_destinations
+            || old.allow_forwarding != allow_forwarding
+            || old.certificate != certificate
+            || old.ca != ca
+            || old.principals != principals
+            || old.allow_rsa_sha1 != allow_rsa_sha1
+            || old.passphrase != passphrase
+            || old.format != format.as_str()
+            || old.organization_id != cipher.organization_id
+        {
+            should_update = true;
+        }
+    } else {
+        should_update = true;
+    }
+
+    if should_update {
+        println!("Updating {}", name);
+
+        ctx.database.add_identity(&IdentityDto {
+            id: cipher.id.clone(),
+            name,
+            public_key: pub_key.clone(),
+            private_key: encrypted_private_key.clone(),
+            intermediate_key: cipher.key.clone(),
+            allowed_destinations,
+            allow_forwarding,
+            certificate,
+            ca,
+            principals,
+            allow_rsa_sha1,
+            passphrase,
+            format: format.as_str().to_string(),
+            organization_id: cipher.organization_id.clone(),
+        })?;
+        summary.changed += 1;
+    }
+
+    summary.new_identities.push(cipher.id.clone());
+
+    Ok(())
 }
 
 pub async fn sync_keys(
@@ -72,108 +570,204 @@ pub async fn sync_keys(
     client: &reqwest::Client,
     config: &ConfigResponseModel,
     symmetric_key: &[u8],
-    auth: &AuthDto,
+    // user rsa private key, needed for organization items
+    private_key: Option<&[u8]>,
+    token_manager: &mut TokenManager<'_>,
 ) -> color_eyre::Result<()> {
     println!("Fetching from {}", config.environment.vault);
 
-    let identity = IdentityClient::new(client, &config.environment.identity, &auth.email);
-    let mut token_manager = TokenManager::new(
-        database,
-        &identity,
-        auth.access_token.clone(),
-        &auth.refresh_token,
-        auth.expires_at,
-    );
-
     let access_token = token_manager.get_access_token().await?;
+    // fetched before the sync, so that changes made while syncing are picked up next time
+    let revision_date = bw_get_revision_date(client, config, access_token).await?;
     let sync_result = bw_sync(client, config, access_token).await?;
 
-    let secure_notes = sync_result
+    let ciphers = sync_result
         .ciphers
         .iter()
-        .filter(|c| {
-            c.type_field == CipherType::SecureNote
-                && c.secure_note.is_some()
-                && c.deleted_date.is_none()
-        })
+        .filter(|c| is_key_cipher(c))
         .collect::<Vec<_>>();
 
-    let mut found = 0;
-    let mut changed = 0;
-    let identities = database.get_identities()?;
-    let mut new_identities = vec![];
-
-    for cipher in secure_notes {
-        let (name, private_key, encrypted_private_key) = {
-            match extract_key_from_cipher(&cipher, symmetric_key) {
-                Ok(Some(keys)) => keys,
-                Ok(None) => continue,
-                Err(e) => {
-                    println!("Error extracting key from cipher id {}: {:?}", cipher.id, e);
+    let organizations = sync_result
+        .profile
+        .as_ref()
+        .map(|profile| profile.organizations.as_slice())
+        .unwrap_or_default();
+    let organization_keys = sync_organizations(database, organizations, private_key)?;
+    let mut missing_organization_keys = 0;
+
+    let ctx = SyncContext {
+        database,
+        client,
+        config,
+        access_token,
+        expose_all_ssh_keys: database.get_bool_setting(EXPOSE_ALL_SSH_KEYS_SETTING)?,
+        identities: database.get_identities()?,
+        cas: database.get_certificate_authorities()?,
+    };
+    let mut summary = SyncSummary::default();
+
+    for cipher in ciphers {
+        let vault_key = match cipher.organization_id {
+            Some(ref organization_id) => match organization_keys.get(organization_id) {
+                Some(key) => key.as_slice(),
+                None => {
+                    missing_organization_keys += 1;
                     continue;
                 }
-            }
+            },
+            None => symmetric_key,
         };
 
-        let ssh_key = match ssh_key::PrivateKey::from_str(&private_key) {
-            Ok(key) => key,
-            Err(e) => {
-                println!(
-                    "Error parsing SSH key from the note named \"{}\": {}",
-                    name, e
-                );
-                continue;
-            }
-        };
-        let pub_key = ssh_key.public_key().to_bytes()?;
+        sync_cipher(&ctx, &mut summary, cipher, vault_key).await?;
+    }
+
+    // delete any identities that are no longer in bitwarden
+    for old in &ctx.identities {
+        if !summary.new_identities.contains(&old.id) {
+            println!("Deleting {}", old.name);
+            database.delete_identity(&old.id)?;
+            summary.changed += 1;
+        }
+    }
 
-        found += 1;
+    for old in &ctx.cas {
+        if !summary.new_cas.contains(&old.id) {
+            println!("Deleting certificate authority {}", old.name);
+            database.delete_certificate_authority(&old.id)?;
+            summary.changed += 1;
+        }
+    }
 
-        let old = identities.iter().find(|i| i.id == cipher.id);
-        let mut should_update = false;
+    database.set_revision_date(revision_date)?;
 
-        if let Some(old) = old {
-            if old.name != name || old.public_key != pub_key {
-                should_update = true;
+    if missing_organization_keys > 0 {
+        println!(
+            "Skipped {} organization items without a usable organization key{}",
+            missing_organization_keys,
+            if private_key.is_none() {
+                ", please log in again to sync organization items"
+            } else {
+                ""
             }
-        } else {
-            should_update = true;
-        }
+        );
+    }
 
-        if should_update {
-            println!("Updating {}", name);
+    summary.print_skipped();
 
-            database.add_identity(&IdentityDto {
-                id: cipher.id.clone(),
-                name,
-                public_key: pub_key.clone(),
-                private_key: encrypted_private_key.clone(),
-                intermediate_key: cipher.key.clone(),
-            })?;
-            changed += 1;
-        }
+    if summary.found == 0 {
+        println!(
+            "No keys to sync. Make sure to put \"{}\" = 1 in a Secure Note or an SSH Key item, or run `bw-ssh-agent config set {} true`.",
+            BW_EXPOSE_FIELD, EXPOSE_ALL_SSH_KEYS_SETTING
+        );
+        return Ok(());
+    }
+
+    println!("Updated {} keys", summary.changed);
 
-        new_identities.push(pub_key);
+    Ok(())
+}
+
+// removes the identity or certificate authority of a deleted item, without talking to the server
+pub fn delete_cipher(database: &Database, cipher_id: &str) -> color_eyre::Result<bool> {
+    let mut deleted = false;
+
+    if let Some(old) = database
+        .get_identities()?
+        .iter()
+        .find(|i| i.id == cipher_id)
+    {
+        println!("Deleting {}", old.name);
+        database.delete_identity(&old.id)?;
+        deleted = true;
     }
 
-    // delete any identities that are no longer in bitwarden
-    for old in identities {
-        if !new_identities.contains(&old.public_key) {
+    if let Some(old) = database
+        .get_certificate_authorities()?
+        .iter()
+        .find(|c| c.id == cipher_id)
+    {
+        println!("Deleting certificate authority {}", old.name);
+        database.delete_certificate_authority(&old.id)?;
+        deleted = true;
+    }
+
+    Ok(deleted)
+}
+
+// refreshes a single key item, e.g. after a push notification about it. the revision date is left
+// alone, since there might be other changes that we haven't seen, which the next full sync will pick up
+pub async fn sync_single_cipher(
+    database: &Database,
+    client: &reqwest::Client,
+    config: &ConfigResponseModel,
+    symmetric_key: &[u8],
+    private_key: Option<&[u8]>,
+    token_manager: &mut TokenManager<'_>,
+    cipher: &CipherDetailsResponseModel,
+) -> color_eyre::Result<()> {
+    let organization_key = match cipher.organization_id {
+        Some(ref organization_id) => {
+            let key = database.get_organization(organization_id)?.zip(private_key);
+            match key {
+                Some((organization, private_key)) => Some(Zeroizing::new(bw_decrypt_rsa_encstr(
+                    private_key,
+                    &organization.key,
+                )?)),
+                // the organization is new to us, its key only comes with a full sync
+                None => {
+                    return sync_keys(
+                        database,
+                        client,
+                        config,
+                        symmetric_key,
+                        private_key,
+                        token_manager,
+                    )
+                    .await
+                }
+            }
+        }
+        None => None,
+    };
+    let vault_key = organization_key
+        .as_deref()
+        .map_or(symmetric_key, Vec::as_slice);
+
+    let access_token = token_manager.get_access_token().await?;
+    let ctx = SyncContext {
+        database,
+        client,
+        config,
+        access_token,
+        expose_all_ssh_keys: database.get_bool_setting(EXPOSE_ALL_SSH_KEYS_SETTING)?,
+        identities: database.get_identities()?,
+        cas: database.get_certificate_authorities()?,
+    };
+    let mut summary = SyncSummary::default();
+
+    sync_cipher(&ctx, &mut summary, cipher, vault_key).await?;
+
+    // e.g. the expose field was removed
+    if let Some(old) = ctx.identities.iter().find(|i| i.id == cipher.id) {
+        if !summary.new_identities.contains(&old.id) {
             println!("Deleting {}", old.name);
             database.delete_identity(&old.id)?;
-            changed += 1;
+            summary.changed += 1;
         }
     }
 
-    if found == 0 {
-        println!(
-            "No keys to sync. Make sure to put \"{}\" = 1 in a Secure Note.",
-            BW_EXPOSE_FIELD
-        );
-        return Ok(());
+    if let Some(old) = ctx.cas.iter().find(|c| c.id == cipher.id) {
+        if !summary.new_cas.contains(&old.id) {
+            println!("Deleting certificate authority {}", old.name);
+            database.delete_certificate_authority(&old.id)?;
+            summary.changed += 1;
+        }
     }
 
-    println!("Updated {} keys", changed);
+    summary.print_skipped();
+    if summary.changed > 0 {
+        println!("Updated {} keys", summary.changed);
+    }
 
     Ok(())
 }
@@ -187,13 +781,33 @@ pub async fn cmd_sync(database: Database) -> color_eyre::Result<()> {
     let client = get_bw_http_client();
     let config = bw_get_config(&client, &auth.vault_url).await?;
 
-    // todo: refresh token if expired
+    let identity = IdentityClient::new(
+        &client,
+        &config.environment.identity,
+        &config.environment.api,
+        &auth.email,
+    );
+    let mut token_manager = TokenManager::new(&database, &identity, &auth);
+    // renew before touching the keychain, so that an expired session doesn't cost a prompt
+    token_manager.get_access_token().await?;
 
     let mut keychain = Keychain::start();
     keychain.ensure_keypair().await?;
     let symmetric_key = keychain.decrypt_data(auth.symmetric_key.to_vec()).await?;
+    let private_key = match auth.private_key {
+        Some(ref private_key) => Some(keychain.decrypt_data(private_key.to_vec()).await?),
+        None => None,
+    };
 
-    sync_keys(&database, &client, &config, &symmetric_key, &auth).await?;
+    sync_keys(
+        &database,
+        &client,
+        &config,
+        &symmetric_key,
+        private_key.as_deref().map(Vec::as_slice),
+        &mut token_manager,
+    )
+    .await?;
 
     Ok(())
 }
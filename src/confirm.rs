@@ -0,0 +1,59 @@
+use std::{env, process::Stdio};
+
+use tokio::process::Command;
+
+// how long to wait for the user before denying the request
+const CONFIRM_TIMEOUT_SECONDS: u32 = 30;
+
+// asks the user to confirm an action.
+// uses $SSH_ASKPASS if set (same as openssh), otherwise falls back to an applescript dialog
+pub async fn confirm(prompt: &str) -> bool {
+    let res = if let Ok(askpass) = env::var("SSH_ASKPASS") {
+        confirm_askpass(&askpass, prompt).await
+    } else {
+        confirm_osascript(prompt).await
+    };
+
+    match res {
+        Ok(confirmed) => confirmed,
+        Err(e) => {
+            eprintln!("Error asking for confirmation: {:?}", e);
+            false
+        }
+    }
+}
+
+async fn confirm_askpass(askpass: &str, prompt: &str) -> color_eyre::Result<bool> {
+    let status = Command::new(askpass)
+        .arg(prompt)
+        .env("SSH_ASKPASS_PROMPT", "confirm")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .await?;
+
+    Ok(status.success())
+}
+
+async fn confirm_osascript(prompt: &str) -> color_eyre::Result<bool> {
+    let script = format!(
+        "display dialog {} with title \"bw-ssh-agent\" buttons {{\"Deny\", \"Allow\"}} default button \"Deny\" giving up after {}",
+        applescript_string(prompt),
+        CONFIRM_TIMEOUT_SECONDS
+    );
+
+    let output = Command::new("osascript")
+        .arg("-e")
+        .arg(script)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    // "Deny" and the timeout both result in a non-"Allow" button
+    Ok(output.status.success()
+        && String::from_utf8_lossy(&output.stdout).contains("button returned:Allow"))
+}
+
+fn applescript_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
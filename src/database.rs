@@ -1,6 +1,7 @@
+use color_eyre::eyre::eyre;
 use rusqlite::params;
 
-use crate::constants::DATABASE_PATH;
+use crate::{bitwarden::crypto::KdfParams, constants::DATABASE_PATH};
 
 #[derive(Debug, Clone)]
 pub struct IdentityDto {
@@ -9,6 +10,50 @@ pub struct IdentityDto {
     pub public_key: Vec<u8>,
     pub private_key: String,
     pub intermediate_key: Option<String>,
+    // host patterns or host key fingerprints the key can be used with (empty = any)
+    pub allowed_destinations: Vec<String>,
+    // whether the key can be used over forwarded agent connections
+    pub allow_forwarding: bool,
+    // openssh certificate for the key (in openssh blob format)
+    pub certificate: Option<Vec<u8>>,
+    // certificate authority to automatically issue certificates with
+    pub ca: Option<String>,
+    // principals for the automatically issued certificates
+    pub principals: Vec<String>,
+    // whether legacy ssh-rsa (SHA-1) signatures are allowed
+    pub allow_rsa_sha1: bool,
+    // encrypted passphrase of the private key
+    pub passphrase: Option<String>,
+    // format of the private key in the vault, see `KeyFormat`
+    pub format: String,
+    // organization owning the item, whose key the item is encrypted with
+    pub organization_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CertificateAuthorityDto {
+    pub id: String,
+    pub name: String,
+    pub public_key: Vec<u8>,
+    pub private_key: String,
+    pub intermediate_key: Option<String>,
+    pub passphrase: Option<String>,
+    pub organization_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OrganizationDto {
+    pub id: String,
+    pub name: String,
+    // organization symmetric key, encrypted with the user rsa public key
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TwoFactorRememberDto {
+    pub email: String,
+    pub device_identifier: String,
+    pub token: String,
 }
 
 #[derive(Debug)]
@@ -20,6 +65,13 @@ pub struct AuthDto {
     pub master_key: Vec<u8>,
     pub symmetric_key: Vec<u8>,
     pub email: String,
+    // encrypted with secure enclave, missing for logins made before organizations were supported
+    pub private_key: Option<Vec<u8>>,
+    // personal api key, set for `login --apikey`
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub device_identifier: Option<String>,
+    pub revision_date: Option<i64>,
 }
 
 pub struct Database {
@@ -29,6 +81,8 @@ pub struct Database {
 impl Database {
     pub fn open() -> color_eyre::Result<Self> {
         let conn = rusqlite::Connection::open(&*DATABASE_PATH)?;
+        // the daemon and the cli might be writing at the same time, wait for each other instead of failing
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
         Database::migrate(&conn)?;
 
         Ok(Self { conn })
@@ -53,6 +107,81 @@ impl Database {
             new_version = 3;
         }
 
+        if new_version == 3 {
+            conn.execute_batch(include_str!("migrations/v4.sql"))?;
+            new_version = 4;
+        }
+
+        if new_version == 4 {
+            conn.execute_batch(include_str!("migrations/v5.sql"))?;
+            new_version = 5;
+        }
+
+        if new_version == 5 {
+            conn.execute_batch(include_str!("migrations/v6.sql"))?;
+            new_version = 6;
+        }
+
+        if new_version == 6 {
+            conn.execute_batch(include_str!("migrations/v7.sql"))?;
+            new_version = 7;
+        }
+
+        if new_version == 7 {
+            conn.execute_batch(include_str!("migrations/v8.sql"))?;
+            new_version = 8;
+        }
+
+        if new_version == 8 {
+            conn.execute_batch(include_str!("migrations/v9.sql"))?;
+            new_version = 9;
+        }
+
+        if new_version == 9 {
+            conn.execute_batch(include_str!("migrations/v10.sql"))?;
+            new_version = 10;
+        }
+
+        if new_version == 10 {
+            conn.execute_batch(include_str!("migrations/v11.sql"))?;
+            new_version = 11;
+        }
+
+        if new_version == 11 {
+            conn.execute_batch(include_str!("migrations/v12.sql"))?;
+            new_version = 12;
+        }
+
+        if new_version == 12 {
+            conn.execute_batch(include_str!("migrations/v13.sql"))?;
+            new_version = 13;
+        }
+
+        if new_version == 13 {
+            conn.execute_batch(include_str!("migrations/v14.sql"))?;
+            new_version = 14;
+        }
+
+        if new_version == 14 {
+            conn.execute_batch(include_str!("migrations/v15.sql"))?;
+            new_version = 15;
+        }
+
+        if new_version == 15 {
+            conn.execute_batch(include_str!("migrations/v16.sql"))?;
+            new_version = 16;
+        }
+
+        if new_version == 16 {
+            conn.execute_batch(include_str!("migrations/v17.sql"))?;
+            new_version = 17;
+        }
+
+        if new_version == 17 {
+            conn.execute_batch(include_str!("migrations/v18.sql"))?;
+            new_version = 18;
+        }
+
         if version != new_version {
             conn.pragma_update(None, "user_version", new_version)?;
         }
@@ -66,6 +195,15 @@ impl Database {
         let public_key: Vec<u8> = row.get(2)?;
         let private_key: String = row.get(3)?;
         let intermediate_key: Option<String> = row.get(4)?;
+        let allowed_destinations: Option<String> = row.get(5)?;
+        let allow_forwarding: bool = row.get(6)?;
+        let certificate: Option<Vec<u8>> = row.get(7)?;
+        let ca: Option<String> = row.get(8)?;
+        let principals: Option<String> = row.get(9)?;
+        let allow_rsa_sha1: bool = row.get(10)?;
+        let passphrase: Option<String> = row.get(11)?;
+        let format: String = row.get(12)?;
+        let organization_id: Option<String> = row.get(13)?;
 
         Ok(IdentityDto {
             id,
@@ -73,6 +211,19 @@ impl Database {
             public_key,
             private_key,
             intermediate_key,
+            allowed_destinations: allowed_destinations
+                .map(|s| s.lines().map(|l| l.to_string()).collect())
+                .unwrap_or_default(),
+            allow_forwarding,
+            certificate,
+            ca,
+            principals: principals
+                .map(|s| s.lines().map(|l| l.to_string()).collect())
+                .unwrap_or_default(),
+            allow_rsa_sha1,
+            passphrase,
+            format,
+            organization_id,
         })
     }
 
@@ -95,40 +246,87 @@ impl Database {
             master_key: row.get(4)?,
             symmetric_key: row.get(5)?,
             email: row.get(6)?,
+            private_key: row.get(7)?,
+            client_id: row.get(8)?,
+            client_secret: row.get(9)?,
+            device_identifier: row.get(10)?,
+            revision_date: row.get(11)?,
         })
     }
 
     pub fn add_identity(&self, dto: &IdentityDto) -> color_eyre::Result<()> {
         self.conn.execute(
-            "INSERT INTO identities (id, name, public_key, private_key, intermediate_key)
-                VALUES (?1, ?2, ?3, ?4, ?5)
+            "INSERT INTO identities (id, name, public_key, private_key, intermediate_key, allowed_destinations, allow_forwarding, certificate, ca, principals, allow_rsa_sha1, passphrase, format, organization_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
                 ON CONFLICT (id) DO UPDATE SET
                     name = excluded.name,
                     public_key = excluded.public_key,
                     private_key = excluded.private_key,
-                    intermediate_key = excluded.intermediate_key",
+                    intermediate_key = excluded.intermediate_key,
+                    allowed_destinations = excluded.allowed_destinations,
+                    allow_forwarding = excluded.allow_forwarding,
+                    certificate = excluded.certificate,
+                    ca = excluded.ca,
+                    principals = excluded.principals,
+                    allow_rsa_sha1 = excluded.allow_rsa_sha1,
+                    passphrase = excluded.passphrase,
+                    format = excluded.format,
+                    organization_id = excluded.organization_id",
             params![
                 dto.id,
                 dto.name,
                 dto.public_key,
                 dto.private_key,
-                dto.intermediate_key
+                dto.intermediate_key,
+                if dto.allowed_destinations.is_empty() {
+                    None
+                } else {
+                    Some(dto.allowed_destinations.join("\n"))
+                },
+                dto.allow_forwarding,
+                dto.certificate,
+                dto.ca,
+                if dto.principals.is_empty() {
+                    None
+                } else {
+                    Some(dto.principals.join("\n"))
+                },
+                dto.allow_rsa_sha1,
+                dto.passphrase,
+                dto.format,
+                dto.organization_id
             ],
         )?;
 
         Ok(())
     }
 
+    // also matches by the certificate blob, so that certificates map back to the underlying key
     pub fn get_identity_by_public_key(
         &self,
         public_key: &[u8],
+    ) -> color_eyre::Result<Option<IdentityDto>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT * FROM identities WHERE public_key = ?1 OR certificate = ?1 LIMIT 1",
+        )?;
+
+        let rows = stmt
+            .query_map([public_key], Database::map_identity)?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn get_identity_by_name_or_id(
+        &self,
+        name_or_id: &str,
     ) -> color_eyre::Result<Option<IdentityDto>> {
         let mut stmt = self
             .conn
-            .prepare_cached("SELECT * FROM identities WHERE public_key = ?1 LIMIT 1")?;
+            .prepare_cached("SELECT * FROM identities WHERE id = ?1 OR name = ?1 LIMIT 1")?;
 
         let rows = stmt
-            .query_map([public_key], Database::map_identity)?
+            .query_map([name_or_id], Database::map_identity)?
             .collect::<Vec<_>>();
 
         Ok(rows.into_iter().flatten().next())
@@ -141,6 +339,148 @@ impl Database {
         Ok(())
     }
 
+    fn map_certificate_authority(
+        row: &rusqlite::Row<'_>,
+    ) -> Result<CertificateAuthorityDto, rusqlite::Error> {
+        Ok(CertificateAuthorityDto {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            public_key: row.get(2)?,
+            private_key: row.get(3)?,
+            intermediate_key: row.get(4)?,
+            passphrase: row.get(5)?,
+            organization_id: row.get(6)?,
+        })
+    }
+
+    pub fn get_certificate_authorities(&self) -> color_eyre::Result<Vec<CertificateAuthorityDto>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM certificate_authorities")?;
+
+        let rows = stmt
+            .query_map([], Database::map_certificate_authority)?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().collect())
+    }
+
+    pub fn get_certificate_authority(
+        &self,
+        name_or_id: &str,
+    ) -> color_eyre::Result<Option<CertificateAuthorityDto>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT * FROM certificate_authorities WHERE id = ?1 OR name = ?1 LIMIT 1",
+        )?;
+
+        let rows = stmt
+            .query_map([name_or_id], Database::map_certificate_authority)?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn add_certificate_authority(
+        &self,
+        dto: &CertificateAuthorityDto,
+    ) -> color_eyre::Result<()> {
+        self.conn.execute(
+            "INSERT INTO certificate_authorities (id, name, public_key, private_key, intermediate_key, passphrase, organization_id)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                ON CONFLICT (id) DO UPDATE SET
+                    name = excluded.name,
+                    public_key = excluded.public_key,
+                    private_key = excluded.private_key,
+                    intermediate_key = excluded.intermediate_key,
+                    passphrase = excluded.passphrase,
+                    organization_id = excluded.organization_id",
+            params![
+                dto.id,
+                dto.name,
+                dto.public_key,
+                dto.private_key,
+                dto.intermediate_key,
+                dto.passphrase,
+                dto.organization_id
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_certificate_authority(&self, id: &str) -> color_eyre::Result<()> {
+        self.conn.execute(
+            "DELETE FROM certificate_authorities WHERE id = ?1",
+            params![id],
+        )?;
+
+        Ok(())
+    }
+
+    fn map_organization(row: &rusqlite::Row<'_>) -> Result<OrganizationDto, rusqlite::Error> {
+        Ok(OrganizationDto {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            key: row.get(2)?,
+        })
+    }
+
+    pub fn get_organizations(&self) -> color_eyre::Result<Vec<OrganizationDto>> {
+        let mut stmt = self.conn.prepare_cached("SELECT * FROM organizations")?;
+
+        let rows = stmt
+            .query_map([], Database::map_organization)?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().collect())
+    }
+
+    pub fn get_organization(&self, id: &str) -> color_eyre::Result<Option<OrganizationDto>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM organizations WHERE id = ?1")?;
+
+        let rows = stmt
+            .query_map([id], Database::map_organization)?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    // organization of a vault item, which has to be known if the item is owned by one
+    pub fn get_owning_organization(
+        &self,
+        organization_id: Option<&String>,
+    ) -> color_eyre::Result<Option<OrganizationDto>> {
+        let Some(organization_id) = organization_id else {
+            return Ok(None);
+        };
+
+        match self.get_organization(organization_id)? {
+            Some(organization) => Ok(Some(organization)),
+            None => Err(eyre!("Organization {organization_id} not found")),
+        }
+    }
+
+    pub fn add_organization(&self, dto: &OrganizationDto) -> color_eyre::Result<()> {
+        self.conn.execute(
+            "INSERT INTO organizations (id, name, key) VALUES (?1, ?2, ?3)
+                ON CONFLICT (id) DO UPDATE SET
+                    name = excluded.name,
+                    key = excluded.key",
+            params![dto.id, dto.name, dto.key],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_organization(&self, id: &str) -> color_eyre::Result<()> {
+        self.conn
+            .execute("DELETE FROM organizations WHERE id = ?1", params![id])?;
+
+        Ok(())
+    }
+
     pub fn get_auth(&self) -> color_eyre::Result<Option<AuthDto>> {
         let mut stmt = self.conn.prepare_cached("SELECT * FROM auth")?;
 
@@ -153,7 +493,8 @@ impl Database {
         // delete any existing auth first
         self.conn.execute("DELETE FROM auth", params![])?;
         self.conn.execute(
-            "INSERT INTO auth (vault_url, access_token, refresh_token, expires_at, master_key, symmetric_key, email) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO auth (vault_url, access_token, refresh_token, expires_at, master_key, symmetric_key, email, private_key, client_id, client_secret, device_identifier, revision_date)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
             params![
                 dto.vault_url,
                 dto.access_token,
@@ -161,17 +502,214 @@ impl Database {
                 dto.expires_at,
                 dto.master_key,
                 dto.symmetric_key,
-                dto.email
+                dto.email,
+                dto.private_key,
+                dto.client_id,
+                dto.client_secret,
+                dto.device_identifier,
+                dto.revision_date
             ],
         )?;
 
         Ok(())
     }
 
-    pub fn update_auth(&self, access_token: &str, expires_at: u64) -> color_eyre::Result<()> {
+    pub fn update_auth(
+        &self,
+        access_token: &str,
+        refresh_token: &str,
+        expires_at: u64,
+    ) -> color_eyre::Result<()> {
+        self.conn.execute(
+            "UPDATE auth SET access_token = ?1, refresh_token = ?2, expires_at = ?3",
+            params![access_token, refresh_token, expires_at],
+        )?;
+
+        Ok(())
+    }
+
+    // forgets the session and everything synced with it. the device and settings are kept
+    pub fn logout(&self) -> color_eyre::Result<()> {
+        self.conn.execute_batch(
+            "BEGIN;
+            DELETE FROM auth;
+            DELETE FROM identities;
+            DELETE FROM certificate_authorities;
+            DELETE FROM organizations;
+            COMMIT;",
+        )?;
+
+        Ok(())
+    }
+
+    // takes the lease if nobody holds it or it has expired, returns whether it was taken
+    pub fn try_acquire_token_lease(
+        &self,
+        holder: &str,
+        now: u64,
+        expires_at: u64,
+    ) -> color_eyre::Result<bool> {
+        let changed = self.conn.execute(
+            "INSERT INTO token_lease (id, holder, expires_at) VALUES (0, ?1, ?2)
+                ON CONFLICT (id) DO UPDATE SET holder = excluded.holder, expires_at = excluded.expires_at
+                WHERE token_lease.expires_at <= ?3",
+            params![holder, expires_at, now],
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    pub fn release_token_lease(&self, holder: &str) -> color_eyre::Result<()> {
+        self.conn
+            .execute("DELETE FROM token_lease WHERE holder = ?1", params![holder])?;
+
+        Ok(())
+    }
+
+    pub fn set_revision_date(&self, revision_date: i64) -> color_eyre::Result<()> {
+        self.conn
+            .execute("UPDATE auth SET revision_date = ?1", params![revision_date])?;
+
+        Ok(())
+    }
+
+    pub fn get_setting(&self, key: &str) -> color_eyre::Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT value FROM settings WHERE key = ?1")?;
+
+        let rows = stmt.query_map([key], |row| row.get(0))?.collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn get_bool_setting(&self, key: &str) -> color_eyre::Result<bool> {
+        Ok(matches!(
+            self.get_setting(key)?.as_deref(),
+            Some("1") | Some("true")
+        ))
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> color_eyre::Result<()> {
+        self.conn.execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+                ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_setting(&self, key: &str) -> color_eyre::Result<()> {
+        self.conn
+            .execute("DELETE FROM settings WHERE key = ?1", params![key])?;
+
+        Ok(())
+    }
+
+    pub fn get_kdf_params(
+        &self,
+        vault_url: &str,
+        email: &str,
+    ) -> color_eyre::Result<Option<KdfParams>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT kdf, iterations, memory, parallelism FROM kdf_params WHERE vault_url = ?1 AND email = ?2",
+        )?;
+
+        let rows = stmt
+            .query_map([vault_url, email], |row| {
+                let kdf: u8 = row.get(0)?;
+                let iterations: u32 = row.get(1)?;
+                let memory: Option<u32> = row.get(2)?;
+                let parallelism: Option<u32> = row.get(3)?;
+
+                Ok(match kdf {
+                    0 => KdfParams::Pbkdf2Sha256 { iterations },
+                    _ => KdfParams::Argon2id {
+                        iterations,
+                        memory: memory.unwrap_or_default(),
+                        parallelism: parallelism.unwrap_or_default(),
+                    },
+                })
+            })?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn set_kdf_params(
+        &self,
+        vault_url: &str,
+        email: &str,
+        kdf: &KdfParams,
+    ) -> color_eyre::Result<()> {
+        let (kdf, iterations, memory, parallelism) = match *kdf {
+            KdfParams::Pbkdf2Sha256 { iterations } => (0, iterations, None, None),
+            KdfParams::Argon2id {
+                iterations,
+                memory,
+                parallelism,
+            } => (1, iterations, Some(memory), Some(parallelism)),
+        };
+
+        self.conn.execute(
+            "INSERT INTO kdf_params (vault_url, email, kdf, iterations, memory, parallelism) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                ON CONFLICT (vault_url, email) DO UPDATE SET
+                    kdf = excluded.kdf,
+                    iterations = excluded.iterations,
+                    memory = excluded.memory,
+                    parallelism = excluded.parallelism",
+            params![vault_url, email, kdf, iterations, memory, parallelism],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_device_identifier(&self) -> color_eyre::Result<Option<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT identifier FROM device LIMIT 1")?;
+
+        let rows = stmt.query_map([], |row| row.get(0))?.collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn set_device_identifier(&self, identifier: &str) -> color_eyre::Result<()> {
+        self.conn.execute("DELETE FROM device", params![])?;
+        self.conn.execute(
+            "INSERT INTO device (identifier) VALUES (?1)",
+            params![identifier],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_two_factor_remember(
+        &self,
+        email: &str,
+    ) -> color_eyre::Result<Option<TwoFactorRememberDto>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT email, device_identifier, token FROM two_factor_remember WHERE email = ?1",
+        )?;
+
+        let rows = stmt
+            .query_map([email], |row| {
+                Ok(TwoFactorRememberDto {
+                    email: row.get(0)?,
+                    device_identifier: row.get(1)?,
+                    token: row.get(2)?,
+                })
+            })?
+            .collect::<Vec<_>>();
+
+        Ok(rows.into_iter().flatten().next())
+    }
+
+    pub fn set_two_factor_remember(&self, dto: &TwoFactorRememberDto) -> color_eyre::Result<()> {
         self.conn.execute(
-            "UPDATE auth SET access_token = ?1, expires_at = ?2",
-            params![access_token, expires_at],
+            "INSERT OR REPLACE INTO two_factor_remember (email, device_identifier, token) VALUES (?1, ?2, ?3)",
+            params![dto.email, dto.device_identifier, dto.token],
         )?;
 
         Ok(())
@@ -0,0 +1,236 @@
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_key::{
+    known_hosts::{Entry, HostPatterns},
+    HashAlg, KnownHosts, PublicKey,
+};
+
+use crate::agent::session::{ConnectionState, SessionBind};
+
+const DEFAULT_SSH_PORT: u16 = 22;
+
+struct KnownHostsCache {
+    // modification time and size of each file, None if it doesn't exist
+    files: Vec<Option<(SystemTime, u64)>>,
+    entries: Arc<Vec<Entry>>,
+}
+
+// known_hosts files can be large, and are checked on every request for a restricted key
+static KNOWN_HOSTS_CACHE: Mutex<Option<KnownHostsCache>> = Mutex::new(None);
+
+fn known_hosts_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("/etc/ssh/ssh_known_hosts")];
+
+    if let Some(dirs) = directories::BaseDirs::new() {
+        let ssh_dir = dirs.home_dir().join(".ssh");
+        paths.push(ssh_dir.join("known_hosts"));
+        paths.push(ssh_dir.join("known_hosts2"));
+    }
+
+    paths
+}
+
+fn read_known_hosts() -> Arc<Vec<Entry>> {
+    let paths = known_hosts_paths();
+    let files = paths
+        .iter()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok()?;
+            Some((metadata.modified().ok()?, metadata.len()))
+        })
+        .collect::<Vec<_>>();
+
+    let mut cache = KNOWN_HOSTS_CACHE
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(ref cache) = *cache {
+        if cache.files == files {
+            return cache.entries.clone();
+        }
+    }
+
+    let entries = Arc::new(
+        paths
+            .into_iter()
+            .filter(|path| path.exists())
+            .flat_map(|path| match KnownHosts::read_file(&path) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    eprintln!("Error reading {}: {}", path.to_string_lossy(), e);
+                    vec![]
+                }
+            })
+            .filter(|entry| entry.marker().is_none())
+            .collect::<Vec<_>>(),
+    );
+
+    *cache = Some(KnownHostsCache {
+        files,
+        entries: entries.clone(),
+    });
+
+    entries
+}
+
+// matches a hostname against a pattern containing `*` and `?` wildcards
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p.eq_ignore_ascii_case(n) => {
+            wildcard_match(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+// `[host]:port` -> (`host`, port)
+fn split_port(name: &str) -> (&str, Option<u16>) {
+    name.strip_prefix('[')
+        .and_then(|name| name.split_once("]:"))
+        .and_then(|(host, port)| Some((host, Some(port.parse().ok()?))))
+        .unwrap_or((name, None))
+}
+
+fn hashed_name_matches(salt: &[u8], hash: &[u8], name: &str) -> bool {
+    let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+        return false;
+    };
+    mac.update(name.as_bytes());
+
+    mac.verify_slice(hash).is_ok()
+}
+
+// patterns without a port match the host on any port
+fn entry_matches(entry: &Entry, pattern: &str) -> bool {
+    let (pattern, port) = split_port(pattern);
+
+    match entry.host_patterns() {
+        HostPatterns::Patterns(names) => names
+            .iter()
+            // known_hosts entries may contain patterns themselves, we only match plain names
+            .filter(|name| !name.starts_with('!') && !name.contains(['*', '?']))
+            .map(|name| split_port(name))
+            .filter(|(_, name_port)| {
+                port.is_none_or(|port| port == name_port.unwrap_or(DEFAULT_SSH_PORT))
+            })
+            .any(|(name, _)| wildcard_match(pattern.as_bytes(), name.as_bytes())),
+        // hashed names can't be enumerated, so we can only match them exactly
+        HostPatterns::HashedName { salt, hash } => {
+            if pattern.contains(['*', '?']) {
+                return false;
+            }
+
+            // same as what ssh hashes, which includes the port unless it's the default one
+            let name = match port {
+                Some(port) if port != DEFAULT_SSH_PORT => format!("[{pattern}]:{port}"),
+                _ => pattern.to_string(),
+            };
+
+            hashed_name_matches(salt, hash, &name.to_ascii_lowercase())
+        }
+    }
+}
+
+fn is_host_key_allowed(host_key: &PublicKey, allowed: &[String], known_hosts: &[Entry]) -> bool {
+    let fingerprint = host_key.fingerprint(HashAlg::Sha256).to_string();
+
+    allowed.iter().any(|pattern| {
+        // host key fingerprints can be used directly
+        if pattern.starts_with("SHA256:") {
+            return *pattern == fingerprint;
+        }
+
+        known_hosts
+            .iter()
+            .filter(|entry| entry.public_key().key_data() == host_key.key_data())
+            .any(|entry| entry_matches(entry, pattern))
+    })
+}
+
+// parses the value of the allowed destinations field.
+// patterns can be separated by commas or whitespace
+pub fn parse_destinations(value: &str) -> Vec<String> {
+    value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+// checks whether a key restricted to the given destinations can be used on the connection.
+// similar to openssh destination constraints, every hop has to be allowed
+pub fn is_destination_allowed(allowed: &[String], session: &ConnectionState) -> bool {
+    if allowed.is_empty() {
+        return true;
+    }
+
+    // restricted keys can't be used without knowing the destination
+    if session.bindings.is_empty() {
+        return false;
+    }
+
+    let known_hosts = read_known_hosts();
+
+    session
+        .bindings
+        .iter()
+        .all(|bind: &SessionBind| is_host_key_allowed(&bind.host_key, allowed, &known_hosts))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    use super::*;
+
+    const HOST_KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIF5AO0va65qPieaX3BhRlms+T05kCuzsUCIuhxt/Maim";
+
+    fn hashed_entry(name: &str) -> Entry {
+        let salt = [42u8; 20];
+        let mut mac = Hmac::<Sha1>::new_from_slice(&salt).unwrap();
+        mac.update(name.as_bytes());
+
+        Entry::from_str(&format!(
+            "|1|{}|{} {HOST_KEY}",
+            BASE64_STANDARD.encode(salt),
+            BASE64_STANDARD.encode(mac.finalize().into_bytes())
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn hashed_names() {
+        let entry = hashed_entry("git.example.com");
+        assert!(entry_matches(&entry, "git.example.com"));
+        assert!(entry_matches(&entry, "[git.example.com]:22"));
+        assert!(!entry_matches(&entry, "*.example.com"));
+
+        let entry = hashed_entry("[git.example.com]:2222");
+        assert!(entry_matches(&entry, "[git.example.com]:2222"));
+        assert!(!entry_matches(&entry, "git.example.com"));
+    }
+
+    #[test]
+    fn plain_names_with_ports() {
+        let entry = Entry::from_str(&format!("[git.example.com]:2222 {HOST_KEY}")).unwrap();
+        assert!(entry_matches(&entry, "git.example.com"));
+        assert!(entry_matches(&entry, "*.example.com"));
+        assert!(entry_matches(&entry, "[*.example.com]:2222"));
+        assert!(!entry_matches(&entry, "[git.example.com]:22"));
+    }
+}
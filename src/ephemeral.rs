@@ -0,0 +1,79 @@
+use ssh_key::PrivateKey;
+
+use crate::utils::get_current_unix_timestamp;
+
+// a key added to the agent at runtime (e.g. via `ssh-add`).
+// it is only ever kept in memory, and ssh-key zeroizes the key material on drop
+pub struct EphemeralIdentity {
+    pub public_key: Vec<u8>,
+    pub private_key: PrivateKey,
+    // unix timestamp after which the key is removed (`ssh-add -t`)
+    pub expires_at: Option<u64>,
+    // whether every signature should be confirmed by the user (`ssh-add -c`)
+    pub confirm: bool,
+}
+
+impl EphemeralIdentity {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| get_current_unix_timestamp() >= expires_at)
+    }
+}
+
+#[derive(Default)]
+pub struct EphemeralStore {
+    identities: Vec<EphemeralIdentity>,
+}
+
+impl EphemeralStore {
+    pub fn add(
+        &mut self,
+        private_key: PrivateKey,
+        expires_at: Option<u64>,
+        confirm: bool,
+    ) -> color_eyre::Result<()> {
+        let public_key = private_key.public_key().to_bytes()?;
+
+        // re-adding an existing key replaces it (same as openssh)
+        self.remove(&public_key);
+        self.identities.push(EphemeralIdentity {
+            public_key,
+            private_key,
+            expires_at,
+            confirm,
+        });
+
+        Ok(())
+    }
+
+    pub fn get(&self, public_key: &[u8]) -> Option<&EphemeralIdentity> {
+        self.iter().find(|i| i.public_key == public_key)
+    }
+
+    pub fn remove(&mut self, public_key: &[u8]) -> bool {
+        let len = self.identities.len();
+        self.identities.retain(|i| i.public_key != public_key);
+
+        self.identities.len() != len
+    }
+
+    pub fn remove_expired(&mut self) {
+        self.identities.retain(|i| {
+            if i.is_expired() {
+                println!("Ephemeral identity {} expired", i.private_key.comment());
+                return false;
+            }
+
+            true
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.identities.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EphemeralIdentity> {
+        // expired keys might not have been cleaned up yet
+        self.identities.iter().filter(|i| !i.is_expired())
+    }
+}
@@ -1,34 +1,344 @@
-use crate::agent::protocol::{Identity, SignatureFlags};
+use crate::agent::protocol::{Identity, KeyConstraint, SignatureFlags};
+use crate::agent::session::ConnectionState;
 use crate::agent::{handler::SSHAgentHandler, protocol::Response};
-use crate::bitwarden::crypto::bw_decrypt_encstr;
-use crate::database::Database;
+use crate::ca::{issue_user_certificate, CertificateOptions, DEFAULT_EXTENSIONS};
+use crate::confirm::confirm;
+use crate::database::{Database, IdentityDto};
+use crate::destination::is_destination_allowed;
+use crate::ephemeral::EphemeralStore;
 use crate::keychain::Keychain;
+use crate::keys::{decrypt_vault_key, get_vault_key, rsa_private_key};
+use crate::utils::get_current_unix_timestamp;
 use color_eyre::eyre::eyre;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
 use sha2::{Sha256, Sha512};
 use signature::SignatureEncoding;
 use signature::Signer;
 use ssh_key::private::KeypairData;
-use ssh_key::PrivateKey;
-use tokio::sync::Mutex;
+use ssh_key::rand_core::{OsRng, RngCore};
+use ssh_key::{HashAlg, PrivateKey};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use tokio::time::Instant;
 use zeroize::Zeroizing;
 
+// the passphrase is never stored, only its salted hash
+struct AgentLock {
+    salt: [u8; 16],
+    hash: Vec<u8>,
+}
+
+impl AgentLock {
+    fn new(passphrase: &[u8]) -> color_eyre::Result<Self> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        let hash = Self::mac(&salt, passphrase)?
+            .finalize()
+            .into_bytes()
+            .to_vec();
+
+        Ok(Self { salt, hash })
+    }
+
+    fn mac(salt: &[u8], passphrase: &[u8]) -> color_eyre::Result<Hmac<Sha512>> {
+        let mut mac = Hmac::<Sha512>::new_from_slice(salt)?;
+        mac.update(passphrase);
+        Ok(mac)
+    }
+
+    fn verify(&self, passphrase: &[u8]) -> bool {
+        match Self::mac(&self.salt, passphrase) {
+            // constant-time comparison
+            Ok(mac) => mac.verify_slice(&self.hash).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct LockState {
+    lock: Option<AgentLock>,
+    fail_count: u32,
+    // unlock attempts are refused until then, so that concurrent attempts are delayed as well
+    not_before: Option<Instant>,
+}
+
+// certificate issued by the daemon for identities backed by a certificate authority
+struct IssuedCertificate {
+    public_key: Vec<u8>,
+    certificate: Vec<u8>,
+    valid_before: u64,
+}
+
 pub struct Handler {
     database: Mutex<Database>,
     keychain: Mutex<Keychain>,
+    lock_state: Mutex<LockState>,
+    ephemeral: Arc<Mutex<EphemeralStore>>,
+    // identity id -> certificate
+    issued_certificates: Mutex<HashMap<String, IssuedCertificate>>,
+    vault_changed: Notify,
 }
 
 impl Handler {
+    // similar to openssh: each failed unlock attempt adds 100ms of delay, up to 3 seconds
+    const UNLOCK_FAIL_DELAY_MS: u64 = 100;
+    const UNLOCK_MAX_FAIL_COUNT: u32 = 30;
+
+    const AUTO_CERTIFICATE_VALIDITY_SECONDS: u64 = 8 * 60 * 60;
+    const AUTO_CERTIFICATE_RENEW_MARGIN_SECONDS: u64 = 60 * 60;
+
     pub fn new(database: Database, keychain: Keychain) -> Self {
         Self {
             database: Mutex::new(database),
             keychain: Mutex::new(keychain),
+            lock_state: Mutex::new(LockState::default()),
+            ephemeral: Arc::new(Mutex::new(EphemeralStore::default())),
+            issued_certificates: Mutex::new(HashMap::new()),
+            vault_changed: Notify::new(),
+        }
+    }
+
+    // used by the background sync, so that the keychain is only accessed from one place
+    pub async fn decrypt_data(&self, data: Vec<u8>) -> color_eyre::Result<Zeroizing<Vec<u8>>> {
+        self.keychain.lock().await.decrypt_data(data).await
+    }
+
+    // identities are read from the database on each request, but issued certificates are cached
+    // and might be signed by a certificate authority that has since changed
+    pub async fn vault_changed(&self) {
+        self.issued_certificates.lock().await.clear();
+        self.vault_changed.notify_one();
+    }
+
+    async fn is_locked(&self) -> bool {
+        self.lock_state.lock().await.lock.is_some()
+    }
+
+    // certificate issued for an identity backed by a certificate authority, if there is a valid one.
+    // issuing needs the keychain, so it's left to renew_certificates
+    async fn issued_certificate(&self, identity: &IdentityDto) -> Option<Vec<u8>> {
+        identity.ca.as_ref()?;
+
+        self.issued_certificates
+            .lock()
+            .await
+            .get(&identity.id)
+            .filter(|cert| {
+                cert.public_key == identity.public_key
+                    && cert.valid_before > get_current_unix_timestamp()
+            })
+            .map(|cert| cert.certificate.clone())
+    }
+
+    // issues certificates for identities backed by a certificate authority that don't have one
+    // yet, or whose certificate is about to expire
+    pub async fn renew_certificates(&self) -> color_eyre::Result<()> {
+        if self.is_locked().await {
+            return Ok(());
+        }
+
+        let identities = {
+            let database = self.database.lock().await;
+            database.get_identities()?
+        };
+
+        let due = {
+            let issued = self.issued_certificates.lock().await;
+            let renew_before =
+                get_current_unix_timestamp() + Self::AUTO_CERTIFICATE_RENEW_MARGIN_SECONDS;
+
+            identities
+                .into_iter()
+                .filter(|identity| identity.ca.is_some())
+                .filter(|identity| match issued.get(&identity.id) {
+                    Some(cert) => {
+                        cert.public_key != identity.public_key || cert.valid_before <= renew_before
+                    }
+                    None => true,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // organization id -> vault key, so that the keychain is only asked once per vault
+        let mut vault_keys = HashMap::new();
+        for identity in due {
+            if let Err(e) = self.issue_certificate(&identity, &mut vault_keys).await {
+                eprintln!("Error issuing certificate for {}: {:?}", identity.name, e);
+            }
         }
+
+        Ok(())
     }
+
+    async fn issue_certificate(
+        &self,
+        identity: &IdentityDto,
+        vault_keys: &mut HashMap<Option<String>, Zeroizing<Vec<u8>>>,
+    ) -> color_eyre::Result<()> {
+        let Some(ref ca_name) = identity.ca else {
+            return Ok(());
+        };
+
+        let (auth, ca, organization) = {
+            let database = self.database.lock().await;
+
+            let Some(ca) = database.get_certificate_authority(ca_name)? else {
+                return Err(eyre!("Certificate authority {ca_name} not found"));
+            };
+
+            let Some(auth) = database.get_auth()? else {
+                return Ok(());
+            };
+
+            let organization = database.get_owning_organization(ca.organization_id.as_ref())?;
+
+            (auth, ca, organization)
+        };
+
+        let vault_key = match vault_keys.entry(ca.organization_id.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                get_vault_key(
+                    &mut *self.keychain.lock().await,
+                    &auth,
+                    organization.as_ref(),
+                )
+                .await?,
+            ),
+        };
+
+        let ca_key = decrypt_vault_key(
+            vault_key,
+            ca.intermediate_key.as_ref(),
+            &ca.private_key,
+            ca.passphrase.as_ref(),
+        )?;
+        let mut public_key = ssh_key::PublicKey::from_bytes(&identity.public_key)?;
+        public_key.set_comment(identity.name.clone());
+
+        let certificate = issue_user_certificate(
+            &ca_key,
+            &public_key,
+            &CertificateOptions {
+                key_id: identity.name.clone(),
+                principals: identity.principals.clone(),
+                validity_seconds: Self::AUTO_CERTIFICATE_VALIDITY_SECONDS,
+                critical_options: vec![],
+                extensions: DEFAULT_EXTENSIONS
+                    .iter()
+                    .map(|name| (name.to_string(), String::new()))
+                    .collect(),
+            },
+        )?;
+
+        println!(
+            "Issued certificate for {} signed by {}",
+            identity.name, ca.name
+        );
+
+        self.issued_certificates.lock().await.insert(
+            identity.id.clone(),
+            IssuedCertificate {
+                public_key: identity.public_key.clone(),
+                certificate: certificate.to_bytes()?,
+                valid_before: certificate.valid_before(),
+            },
+        );
+
+        Ok(())
+    }
+
+    // waits until the vault has changed, or the timeout has passed
+    pub async fn wait_for_vault_change(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.vault_changed.notified()).await;
+    }
+
+    // maps certificates issued by the daemon back to the underlying public key
+    async fn resolve_issued_certificate(&self, pubkey: Vec<u8>) -> Vec<u8> {
+        self.issued_certificates
+            .lock()
+            .await
+            .values()
+            .find(|cert| cert.certificate == pubkey)
+            .map(|cert| cert.public_key.clone())
+            .unwrap_or(pubkey)
+    }
+}
+
+fn sign_with_key(
+    private_key: &PrivateKey,
+    name: &str,
+    data: &[u8],
+    flags: u32,
+    allow_rsa_sha1: bool,
+) -> color_eyre::Result<Response> {
+    let (signature, algo_name) = match private_key.key_data() {
+        KeypairData::Rsa(keypair) => {
+            let flags = SignatureFlags::from_bits_truncate(flags);
+            let rsa_key = rsa_private_key(keypair)?;
+
+            let res = if flags.intersects(SignatureFlags::SSH_AGENT_RSA_SHA2_256) {
+                (
+                    rsa::pkcs1v15::SigningKey::<Sha256>::new(rsa_key)
+                        .try_sign(data)?
+                        .to_vec(),
+                    String::from("rsa-sha2-256"),
+                )
+            } else if flags.intersects(SignatureFlags::SSH_AGENT_RSA_SHA2_512) {
+                (
+                    rsa::pkcs1v15::SigningKey::<Sha512>::new(rsa_key)
+                        .try_sign(data)?
+                        .to_vec(),
+                    String::from("rsa-sha2-512"),
+                )
+            } else if allow_rsa_sha1 {
+                println!("Warning: signing with legacy ssh-rsa (SHA-1) using key {name}");
+                (
+                    rsa::pkcs1v15::SigningKey::<Sha1>::new(rsa_key)
+                        .try_sign(data)?
+                        .to_vec(),
+                    String::from("ssh-rsa"),
+                )
+            } else {
+                Err(eyre!(
+                    "Server requested RSA SHA-1, but it's not allowed for key {name}"
+                ))?
+            };
+
+            res
+        }
+        // other algorithms do not depend on the server request.
+        // for ecdsa, the curve determines the hash, and the signature is encoded as (mpint r, mpint s)
+        KeypairData::Ed25519(_) | KeypairData::Ecdsa(_) => {
+            let res = private_key.try_sign(data)?;
+            // as_bytes() is the signature blob, while to_bytes() would also include the algorithm
+            (res.as_bytes().to_vec(), res.algorithm().to_string())
+        }
+        _ => Err(eyre!(
+            "Unsupported key algorithm: {}",
+            private_key.algorithm()
+        ))?,
+    };
+
+    Ok(Response::SignResponse {
+        algo_name,
+        signature,
+    })
 }
 
 #[async_trait::async_trait]
 impl SSHAgentHandler for Handler {
-    async fn identities(&self) -> color_eyre::Result<Response> {
+    async fn identities(&self, session: &ConnectionState) -> color_eyre::Result<Response> {
+        if self.is_locked().await {
+            return Ok(Response::Identities(vec![]));
+        }
+
         let db_idents = {
             let database = self.database.lock().await;
             database.get_identities()?
@@ -36,11 +346,47 @@ impl SSHAgentHandler for Handler {
 
         let mut idents = Vec::new();
         for db_ident in db_idents {
+            // unbound connections still see restricted keys (same as openssh),
+            // but they will be refused when signing
+            if !session.bindings.is_empty()
+                && !is_destination_allowed(&db_ident.allowed_destinations, session)
+            {
+                continue;
+            }
+
+            if session.is_forwarded() && !db_ident.allow_forwarding {
+                continue;
+            }
+
+            let auto_certificate = self.issued_certificate(&db_ident).await;
+
             idents.push(Identity {
                 key_blob: db_ident.public_key,
                 key_comment: db_ident.name.clone(),
             });
+
+            if let Some(certificate) = auto_certificate {
+                idents.push(Identity {
+                    key_blob: certificate,
+                    key_comment: db_ident.name.clone(),
+                });
+            }
+
+            if let Some(certificate) = db_ident.certificate {
+                idents.push(Identity {
+                    key_blob: certificate,
+                    key_comment: db_ident.name.clone(),
+                });
+            }
         }
+
+        for ident in self.ephemeral.lock().await.iter() {
+            idents.push(Identity {
+                key_blob: ident.public_key.clone(),
+                key_comment: ident.private_key.comment().to_string(),
+            });
+        }
+
         Ok(Response::Identities(idents))
     }
 
@@ -49,8 +395,57 @@ impl SSHAgentHandler for Handler {
         pubkey: Vec<u8>,
         data: Vec<u8>,
         flags: u32,
+        session: &ConnectionState,
     ) -> color_eyre::Result<Response> {
-        let (auth, identity) = {
+        if self.is_locked().await {
+            return Ok(Response::Failure);
+        }
+
+        // the host is only known if the data is a login to the session the connection is bound to
+        let destination = session.verify_user_auth(&data, &pubkey);
+        if let Ok(destination) = destination {
+            println!(
+                "Sign request for host {}{}",
+                destination.host_key_fingerprint(),
+                if session.is_forwarded() {
+                    " (forwarded)"
+                } else {
+                    ""
+                }
+            );
+        }
+        let destination_name = match destination {
+            Ok(destination) => destination.host_key_fingerprint(),
+            Err(_) => String::from("(unknown host)"),
+        };
+
+        let ephemeral = self
+            .ephemeral
+            .lock()
+            .await
+            .get(&pubkey)
+            .map(|i| (i.private_key.clone(), i.confirm));
+
+        if let Some((private_key, needs_confirm)) = ephemeral {
+            if needs_confirm {
+                let prompt = format!(
+                    "Allow use of key {}?\nKey fingerprint {}.",
+                    private_key.comment(),
+                    private_key.fingerprint(HashAlg::Sha256)
+                );
+
+                if !confirm(&prompt).await {
+                    println!("Use of key {} was denied", private_key.comment());
+                    return Ok(Response::Failure);
+                }
+            }
+
+            return sign_with_key(&private_key, private_key.comment(), &data, flags, false);
+        }
+
+        let pubkey = self.resolve_issued_certificate(pubkey).await;
+
+        let (auth, identity, organization) = {
             let database = self.database.lock().await;
 
             let Some(identity) = database.get_identity_by_public_key(&pubkey)? else {
@@ -61,58 +456,183 @@ impl SSHAgentHandler for Handler {
                 return Ok(Response::Failure);
             };
 
-            (auth, identity)
+            let organization =
+                database.get_owning_organization(identity.organization_id.as_ref())?;
+
+            (auth, identity, organization)
         };
 
-        let mut symmetric_key = self
-            .keychain
+        if !is_destination_allowed(&identity.allowed_destinations, session) {
+            println!(
+                "Refusing to use key {} for host {}: destination not allowed",
+                identity.name, destination_name
+            );
+            return Ok(Response::Failure);
+        }
+
+        // otherwise an allowed host holding a forwarded agent could get signatures for other hosts
+        if !identity.allowed_destinations.is_empty() {
+            if let Err(ref e) = destination {
+                println!("Refusing to use key {}: {e}", identity.name);
+                return Ok(Response::Failure);
+            }
+        }
+
+        if session.is_forwarded() && !identity.allow_forwarding {
+            println!(
+                "Refusing to use key {} for host {}: key is not allowed over forwarded connections",
+                identity.name, destination_name
+            );
+            return Ok(Response::Failure);
+        }
+
+        let vault_key = get_vault_key(
+            &mut *self.keychain.lock().await,
+            &auth,
+            organization.as_ref(),
+        )
+        .await?;
+
+        let private_key = decrypt_vault_key(
+            &vault_key,
+            identity.intermediate_key.as_ref(),
+            &identity.private_key,
+            identity.passphrase.as_ref(),
+        )?;
+
+        sign_with_key(
+            &private_key,
+            &identity.name,
+            &data,
+            flags,
+            identity.allow_rsa_sha1,
+        )
+    }
+
+    async fn add_identity(
+        &self,
+        key: KeypairData,
+        comment: String,
+        constraints: Vec<KeyConstraint>,
+    ) -> color_eyre::Result<Response> {
+        if self.is_locked().await {
+            return Ok(Response::Failure);
+        }
+
+        let mut lifetime = None;
+        let mut needs_confirm = false;
+        for constraint in constraints {
+            match constraint {
+                KeyConstraint::Lifetime(seconds) => lifetime = Some(seconds),
+                KeyConstraint::Confirm => needs_confirm = true,
+                // the spec requires refusing keys with constraints we don't understand
+                KeyConstraint::Extension { name } => {
+                    println!("Refusing to add key {comment}: unsupported constraint {name}");
+                    return Ok(Response::Failure);
+                }
+                KeyConstraint::Unknown(kind) => {
+                    println!("Refusing to add key {comment}: unknown constraint type {kind}");
+                    return Ok(Response::Failure);
+                }
+            }
+        }
+
+        let private_key = PrivateKey::new(key, comment)?;
+        println!("Adding ephemeral identity {}", private_key.comment());
+
+        let expires_at = lifetime.map(|seconds| get_current_unix_timestamp() + seconds as u64);
+        self.ephemeral
             .lock()
             .await
-            .decrypt_data(auth.symmetric_key.to_vec())
-            .await?;
-
-        if let Some(intermediate_key) = &identity.intermediate_key {
-            symmetric_key = Zeroizing::new(bw_decrypt_encstr(&symmetric_key, intermediate_key)?);
-        }
-
-        let private_key = Zeroizing::new(bw_decrypt_encstr(&symmetric_key, &identity.private_key)?);
-
-        let private_key = PrivateKey::from_openssh(private_key)?;
-
-        let (signature, algo_name) = match private_key.key_data() {
-            KeypairData::Rsa(keypair) => {
-                let flags = SignatureFlags::from_bits_truncate(flags);
-
-                let res = if flags.intersects(SignatureFlags::SSH_AGENT_RSA_SHA2_256) {
-                    (
-                        rsa::pkcs1v15::SigningKey::<Sha256>::try_from(keypair)?
-                            .try_sign(&data)?
-                            .to_vec(),
-                        String::from("rsa-sha2-256"),
-                    )
-                } else if flags.intersects(SignatureFlags::SSH_AGENT_RSA_SHA2_512) {
-                    (
-                        rsa::pkcs1v15::SigningKey::<Sha512>::try_from(keypair)?
-                            .try_sign(&data)?
-                            .to_vec(),
-                        String::from("rsa-sha2-512"),
-                    )
-                } else {
-                    Err(eyre!("Server requested RSA SHA-1, but it's not supported"))?
-                };
+            .add(private_key, expires_at, needs_confirm)?;
 
-                res
+        if let Some(seconds) = lifetime {
+            let ephemeral = self.ephemeral.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(seconds as u64)).await;
+                ephemeral.lock().await.remove_expired();
+            });
+        }
+
+        Ok(Response::Success)
+    }
+
+    async fn remove_identity(&self, pubkey: Vec<u8>) -> color_eyre::Result<Response> {
+        if self.is_locked().await {
+            return Ok(Response::Failure);
+        }
+
+        // only ephemeral identities can be removed, vault ones are managed by the sync
+        if self.ephemeral.lock().await.remove(&pubkey) {
+            Ok(Response::Success)
+        } else {
+            Ok(Response::Failure)
+        }
+    }
+
+    async fn remove_all_identities(&self) -> color_eyre::Result<Response> {
+        if self.is_locked().await {
+            return Ok(Response::Failure);
+        }
+
+        self.ephemeral.lock().await.clear();
+
+        Ok(Response::Success)
+    }
+
+    async fn lock(&self, passphrase: Vec<u8>) -> color_eyre::Result<Response> {
+        let passphrase = Zeroizing::new(passphrase);
+        let mut state = self.lock_state.lock().await;
+
+        if state.lock.is_some() {
+            return Ok(Response::Failure);
+        }
+
+        state.lock = Some(AgentLock::new(&passphrase)?);
+        println!("Agent locked");
+
+        Ok(Response::Success)
+    }
+
+    async fn unlock(&self, passphrase: Vec<u8>) -> color_eyre::Result<Response> {
+        let passphrase = Zeroizing::new(passphrase);
+
+        let delay = {
+            let mut state = self.lock_state.lock().await;
+
+            let Some(ref lock) = state.lock else {
+                return Ok(Response::Failure);
+            };
+
+            if state
+                .not_before
+                .is_some_and(|not_before| Instant::now() < not_before)
+            {
+                return Ok(Response::Failure);
+            }
+
+            if lock.verify(&passphrase) {
+                state.lock = None;
+                state.fail_count = 0;
+                state.not_before = None;
+                println!("Agent unlocked");
+
+                return Ok(Response::Success);
             }
-            // other algorithms do not depend on the server request
-            _ => {
-                let res = private_key.try_sign(data.as_slice())?;
-                (res.to_bytes(), res.algorithm().to_string())
+
+            if state.fail_count < Self::UNLOCK_MAX_FAIL_COUNT {
+                state.fail_count += 1;
             }
+
+            let delay = Duration::from_millis(Self::UNLOCK_FAIL_DELAY_MS * state.fail_count as u64);
+            state.not_before = Some(Instant::now() + delay);
+
+            delay
         };
 
-        Ok(Response::SignResponse {
-            algo_name,
-            signature,
-        })
+        // only the failed attempt waits, other requests can still use the lock state
+        tokio::time::sleep(delay).await;
+
+        Ok(Response::Failure)
     }
 }
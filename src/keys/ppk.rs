@@ -0,0 +1,272 @@
+// putty private key files, see https://the.earth.li/~sgtatham/putty/0.80/htmldoc/AppendixC.html
+use std::collections::HashMap;
+
+use aes::cipher::{block_padding::NoPadding, BlockDecryptMut as _, KeyIvInit as _};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use color_eyre::eyre::eyre;
+use hmac::{Hmac, Mac};
+use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use ssh_encoding::{Decode, Encode};
+use ssh_key::{
+    private::{Ed25519Keypair, Ed25519PrivateKey, KeypairData, RsaKeypair, RsaPrivateKey},
+    public::{EcdsaPublicKey, KeyData},
+    Mpint, PrivateKey,
+};
+use zeroize::Zeroizing;
+
+use super::ecdsa_keypair;
+
+const MAC_KEY_PREFIX_V2: &[u8] = b"putty-private-key-file-mac-key";
+const ARGON2_OUTPUT_LENGTH: usize = 80;
+
+struct PpkFile<'a> {
+    version: u8,
+    algorithm: &'a str,
+    headers: HashMap<&'a str, &'a str>,
+    public_blob: Vec<u8>,
+    private_blob: Zeroizing<Vec<u8>>,
+}
+
+impl<'a> PpkFile<'a> {
+    fn parse(text: &'a str) -> color_eyre::Result<Self> {
+        let mut lines = text.trim().lines();
+        let mut version = None;
+        let mut headers = HashMap::new();
+        let mut public_blob = None;
+        let mut private_blob = None;
+
+        while let Some(line) = lines.next() {
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(eyre!("malformed putty key line: {line}"));
+            };
+            let value = value.trim();
+
+            match name {
+                "PuTTY-User-Key-File-2" => version = Some((2, value)),
+                "PuTTY-User-Key-File-3" => version = Some((3, value)),
+                "Public-Lines" | "Private-Lines" => {
+                    let count: usize = value.parse()?;
+                    let mut data = Zeroizing::new(String::new());
+                    for _ in 0..count {
+                        let line = lines.next().ok_or_else(|| eyre!("truncated putty key"))?;
+                        data.push_str(line.trim());
+                    }
+
+                    let data = BASE64_STANDARD.decode(data.as_bytes())?;
+                    if name == "Public-Lines" {
+                        public_blob = Some(data);
+                    } else {
+                        private_blob = Some(Zeroizing::new(data));
+                    }
+                }
+                _ => {
+                    headers.insert(name, value);
+                }
+            }
+        }
+
+        let Some((version, algorithm)) = version else {
+            return Err(eyre!("not a putty key"));
+        };
+
+        Ok(Self {
+            version,
+            algorithm,
+            headers,
+            public_blob: public_blob.ok_or_else(|| eyre!("putty key has no public part"))?,
+            private_blob: private_blob.ok_or_else(|| eyre!("putty key has no private part"))?,
+        })
+    }
+
+    fn header(&self, name: &str) -> color_eyre::Result<&'a str> {
+        self.headers
+            .get(name)
+            .copied()
+            .ok_or_else(|| eyre!("putty key is missing the {name} header"))
+    }
+
+    // returns the aes key, iv and mac key
+    #[allow(clippy::type_complexity)]
+    fn derive_keys(
+        &self,
+        passphrase: &[u8],
+        encrypted: bool,
+    ) -> color_eyre::Result<(Zeroizing<Vec<u8>>, [u8; 16], Zeroizing<Vec<u8>>)> {
+        if self.version == 2 {
+            let mut cipher_key = Zeroizing::new(Vec::with_capacity(40));
+            for counter in [0u32, 1] {
+                let mut hasher = Sha1::new();
+                hasher.update(counter.to_be_bytes());
+                hasher.update(passphrase);
+                cipher_key.extend_from_slice(&hasher.finalize());
+            }
+            cipher_key.truncate(32);
+
+            let mut hasher = Sha1::new();
+            hasher.update(MAC_KEY_PREFIX_V2);
+            hasher.update(passphrase);
+            let mac_key = Zeroizing::new(hasher.finalize().to_vec());
+
+            return Ok((cipher_key, [0u8; 16], mac_key));
+        }
+
+        // unencrypted v3 keys use an empty mac key
+        if !encrypted {
+            return Ok((Zeroizing::new(vec![]), [0u8; 16], Zeroizing::new(vec![])));
+        }
+
+        let algorithm = match self.header("Key-Derivation")? {
+            "Argon2id" => argon2::Algorithm::Argon2id,
+            "Argon2i" => argon2::Algorithm::Argon2i,
+            "Argon2d" => argon2::Algorithm::Argon2d,
+            other => return Err(eyre!("unsupported putty key derivation {other}")),
+        };
+
+        let params = argon2::Params::new(
+            self.header("Argon2-Memory")?.parse()?,
+            self.header("Argon2-Passes")?.parse()?,
+            self.header("Argon2-Parallelism")?.parse()?,
+            Some(ARGON2_OUTPUT_LENGTH),
+        )
+        .map_err(|e| eyre!("invalid argon2 parameters: {e}"))?;
+        let salt = decode_hex(self.header("Argon2-Salt")?)?;
+
+        let mut output = Zeroizing::new([0u8; ARGON2_OUTPUT_LENGTH]);
+        argon2::Argon2::new(algorithm, argon2::Version::V0x13, params)
+            .hash_password_into(passphrase, &salt, output.as_mut_slice())
+            .map_err(|e| eyre!("argon2 failed: {e}"))?;
+
+        let mut iv = [0u8; 16];
+        iv.copy_from_slice(&output[32..48]);
+
+        Ok((
+            Zeroizing::new(output[..32].to_vec()),
+            iv,
+            Zeroizing::new(output[48..].to_vec()),
+        ))
+    }
+}
+
+// returns the decoded key and whether it was encrypted
+pub fn decode_ppk_key(
+    text: &str,
+    passphrase: Option<&[u8]>,
+) -> color_eyre::Result<(PrivateKey, bool)> {
+    let mut file = PpkFile::parse(text)?;
+    let encryption = file.header("Encryption")?;
+    let comment = file.header("Comment").unwrap_or_default();
+
+    let encrypted = match encryption {
+        "none" => false,
+        "aes256-cbc" => true,
+        other => return Err(eyre!("unsupported putty key encryption {other}")),
+    };
+
+    let passphrase = match (encrypted, passphrase) {
+        (false, _) => &[][..],
+        (true, Some(passphrase)) => passphrase,
+        (true, None) => return Err(eyre!("no passphrase is set")),
+    };
+
+    let (cipher_key, iv, mac_key) = file.derive_keys(passphrase, encrypted)?;
+
+    if encrypted {
+        let cipher = cbc::Decryptor::<aes::Aes256>::new(cipher_key.as_slice().into(), (&iv).into());
+        cipher
+            .decrypt_padded_mut::<NoPadding>(&mut file.private_blob)
+            .map_err(|_| eyre!("putty key private part is not block aligned"))?;
+    }
+
+    // the mac covers the decrypted private part, so this is also the passphrase check
+    let mut mac_data = Zeroizing::new(vec![]);
+    for field in [
+        file.algorithm.as_bytes(),
+        encryption.as_bytes(),
+        comment.as_bytes(),
+        file.public_blob.as_slice(),
+        file.private_blob.as_slice(),
+    ] {
+        field.encode(&mut *mac_data)?;
+    }
+
+    let expected_mac = decode_hex(file.header("Private-MAC")?)?;
+    let mac_ok = if file.version == 2 {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&mac_key)?;
+        mac.update(&mac_data);
+        mac.verify_slice(&expected_mac).is_ok()
+    } else {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&mac_key)?;
+        mac.update(&mac_data);
+        mac.verify_slice(&expected_mac).is_ok()
+    };
+
+    if !mac_ok {
+        return Err(if encrypted {
+            eyre!("wrong passphrase")
+        } else {
+            eyre!("putty key is corrupted (mac mismatch)")
+        });
+    }
+
+    let public_key = KeyData::decode(&mut file.public_blob.as_slice())?;
+    let reader = &mut file.private_blob.as_slice();
+
+    let keypair = match public_key {
+        KeyData::Rsa(public) => {
+            let d = Mpint::decode(reader)?;
+            let p = Mpint::decode(reader)?;
+            let q = Mpint::decode(reader)?;
+            let iqmp = Mpint::decode(reader)?;
+
+            KeypairData::Rsa(RsaKeypair {
+                public,
+                private: RsaPrivateKey { d, iqmp, p, q },
+            })
+        }
+        KeyData::Ed25519(public) => {
+            let seed = Zeroizing::new(Vec::<u8>::decode(reader)?);
+            let keypair = Ed25519Keypair::from(Ed25519PrivateKey::try_from(seed.as_slice())?);
+
+            if keypair.public != public {
+                return Err(eyre!("putty key public and private parts don't match"));
+            }
+
+            KeypairData::Ed25519(keypair)
+        }
+        KeyData::Ecdsa(public) => {
+            let scalar = Mpint::decode(reader)?;
+            let scalar = scalar
+                .as_positive_bytes()
+                .ok_or_else(|| eyre!("invalid ecdsa private key"))?;
+            let keypair = ecdsa_keypair(public.curve(), scalar)?;
+
+            if EcdsaPublicKey::from(&keypair) != public {
+                return Err(eyre!("putty key public and private parts don't match"));
+            }
+
+            KeypairData::Ecdsa(keypair)
+        }
+        _ => return Err(eyre!("unsupported putty key algorithm {}", file.algorithm)),
+    };
+
+    Ok((PrivateKey::new(keypair, comment)?, encrypted))
+}
+
+fn decode_hex(value: &str) -> color_eyre::Result<Vec<u8>> {
+    let digit = |c: u8| {
+        (c as char)
+            .to_digit(16)
+            .map(|d| d as u8)
+            .ok_or_else(|| eyre!("invalid hex value"))
+    };
+
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Ok((digit(*high)? << 4) | digit(*low)?),
+            _ => Err(eyre!("invalid hex value")),
+        })
+        .collect()
+}
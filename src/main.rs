@@ -1,20 +1,26 @@
-use std::fs;
+use std::{fs, path::PathBuf};
 
 use clap::{command, Parser, Subcommand};
 use cmd::{
-    daemon_register::cmd_daemon_register, daemon_run::cmd_daemon_run, list::cmd_list,
-    login::cmd_login, sync::cmd_sync,
+    cert::cmd_cert_issue, config::cmd_config, daemon_register::cmd_daemon_register,
+    daemon_run::cmd_daemon_run, list::cmd_list, login::cmd_login, sync::cmd_sync,
 };
 use constants::DATA_DIR;
 use database::Database;
 
 pub mod agent;
+pub mod background_sync;
 pub mod bitwarden;
+pub mod ca;
 pub mod cmd;
+pub mod confirm;
 pub mod constants;
 pub mod database;
+pub mod destination;
+pub mod ephemeral;
 pub mod handler;
 pub mod keychain;
+pub mod keys;
 pub mod utils;
 
 #[derive(Clone, Debug, Subcommand)]
@@ -25,6 +31,48 @@ pub enum DaemonCommands {
     Register,
 }
 
+#[derive(Clone, Debug, Subcommand)]
+pub enum CertCommands {
+    /// Issues a user certificate for an identity using a certificate authority from the vault
+    Issue {
+        /// Name or id of the identity to issue the certificate for
+        identity: String,
+        /// Name or id of the certificate authority (can be omitted if there's only one)
+        #[arg(long)]
+        ca: Option<String>,
+        /// Principal (user name) the certificate is valid for
+        #[arg(short = 'n', long = "principal", required = true)]
+        principals: Vec<String>,
+        /// Validity period of the certificate, e.g. 30m, 8h, 7d
+        #[arg(short = 'V', long, default_value = "8h")]
+        validity: String,
+        /// Key identifier (defaults to the identity name)
+        #[arg(short = 'I', long)]
+        key_id: Option<String>,
+        /// Critical option in `name=value` format, e.g. force-command=ls
+        #[arg(long = "critical-option")]
+        critical_options: Vec<String>,
+        /// Extension in `name` or `name=value` format (defaults to the ssh-keygen defaults)
+        #[arg(long = "extension")]
+        extensions: Vec<String>,
+        /// File to write the certificate to (defaults to stdout)
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Debug, Subcommand)]
+pub enum ConfigCommands {
+    /// Lists the available settings and their values
+    List,
+    /// Prints the value of a setting
+    Get { key: String },
+    /// Changes the value of a setting
+    Set { key: String, value: String },
+    /// Resets a setting to its default value
+    Unset { key: String },
+}
+
 #[derive(Debug, Subcommand)]
 pub enum Commands {
     /// Daemon controls
@@ -38,13 +86,40 @@ pub enum Commands {
         email: Option<String>,
         #[arg(long)]
         password: Option<String>,
+        /// Read the master password from the first line of stdin
+        #[arg(long, conflicts_with_all = ["password", "password_fd"])]
+        password_stdin: bool,
+        /// Read the master password from the first line of the given file descriptor
+        #[arg(long, value_name = "FD", conflicts_with = "password")]
+        password_fd: Option<i32>,
+        /// Log in with a personal API key (taken from BW_CLIENTID and BW_CLIENTSECRET, or prompted for).
+        /// The master password is still needed to decrypt the vault
+        #[arg(long, conflicts_with = "device")]
+        apikey: bool,
+        /// Log in by approving the request from another device logged into the account,
+        /// without entering the master password
+        #[arg(long, conflicts_with_all = ["password", "password_stdin", "password_fd"])]
+        device: bool,
         #[arg(long)]
         vault_url: Option<String>,
+        /// Log in even if the server returns weaker KDF parameters than it did before
+        #[arg(long)]
+        allow_kdf_downgrade: bool,
     },
     /// Syncs the private keys from the vault into the agent
     Sync,
     /// Lists the identities in the agent database
     List,
+    /// Certificate authority controls
+    Cert {
+        #[command(subcommand)]
+        subcommand: CertCommands,
+    },
+    /// Agent settings
+    Config {
+        #[command(subcommand)]
+        subcommand: ConfigCommands,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -80,6 +155,12 @@ async fn main() -> color_eyre::Result<()> {
         Commands::List => {
             cmd_list(database)?;
         }
+        Commands::Cert { subcommand } => match subcommand {
+            CertCommands::Issue { .. } => cmd_cert_issue(database, subcommand).await?,
+        },
+        Commands::Config { subcommand } => {
+            cmd_config(database, subcommand)?;
+        }
     };
 
     Ok(())
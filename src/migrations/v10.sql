@@ -0,0 +1,2 @@
+-- original format of the private key stored in the vault
+alter table identities add column format text not null default 'openssh';
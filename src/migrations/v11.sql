@@ -0,0 +1,5 @@
+-- user settings, see `cmd/config.rs`
+create table settings (
+    key text primary key,
+    value text not null
+);
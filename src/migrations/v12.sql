@@ -0,0 +1,13 @@
+-- user rsa private key (pkcs#8 der), encrypted with secure enclave
+alter table auth add column private_key blob;
+
+create table organizations (
+    -- uuid from bitwarden vault
+    id text primary key,
+    name text not null,
+    -- organization key, encrypted with the user rsa public key
+    key text not null
+);
+
+alter table identities add column organization_id text;
+alter table certificate_authorities add column organization_id text;
@@ -0,0 +1,13 @@
+-- kdf parameters seen for each account, to detect downgrades by the server.
+-- the same email can have accounts on different servers
+create table kdf_params (
+    vault_url text not null,
+    email text not null,
+    -- 0 = pbkdf2-sha256, 1 = argon2id
+    kdf integer not null,
+    iterations integer not null,
+    -- argon2 only
+    memory integer,
+    parallelism integer,
+    primary key (vault_url, email)
+);
@@ -0,0 +1,6 @@
+-- two-factor "remember me" tokens, only valid together with the device identifier they were issued for
+create table two_factor_remember (
+    email text primary key,
+    device_identifier text not null,
+    token text not null
+);
@@ -0,0 +1,5 @@
+-- personal api key, used instead of the refresh token for logins made with `login --apikey`
+alter table auth add column client_id text;
+alter table auth add column client_secret text;
+-- device identifier the session was created with
+alter table auth add column device_identifier text;
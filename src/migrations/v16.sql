@@ -0,0 +1,16 @@
+-- identifier this installation logs in with, so that the server sees it as the same device every time
+create table device (
+    identifier text not null
+);
+
+-- keep the identifier existing logins were made with, so that the device isn't registered again
+-- and the two-factor "remember me" token stays valid
+insert into device (identifier)
+select identifier from (
+    select device_identifier as identifier, 0 as priority from auth where device_identifier is not null
+    union all
+    select device_identifier, case when email in (select email from auth) then 1 else 2 end
+    from two_factor_remember
+)
+order by priority
+limit 1;
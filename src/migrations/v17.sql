@@ -0,0 +1,2 @@
+-- account revision date (ms) at the last sync, to skip syncing (and the keychain prompt) when nothing changed
+alter table auth add column revision_date integer;
@@ -0,0 +1,7 @@
+-- lease for renewing the access token, so that the daemon and the cli don't use the refresh token at the same time.
+-- expires on its own, in case the holder died while renewing
+create table token_lease (
+    id integer primary key check (id = 0),
+    holder text not null,
+    expires_at integer not null
+);
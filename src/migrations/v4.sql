@@ -0,0 +1 @@
+alter table identities add column allowed_destinations text;
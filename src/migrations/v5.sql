@@ -0,0 +1 @@
+alter table identities add column allow_forwarding integer not null default 0;
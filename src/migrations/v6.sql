@@ -0,0 +1 @@
+alter table identities add column certificate blob;
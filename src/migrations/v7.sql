@@ -0,0 +1,15 @@
+create table certificate_authorities (
+    -- uuid from bitwarden vault
+    id text primary key,
+    -- name from bitwarden vault
+    name text not null,
+    -- public key (in openssh blob format)
+    public_key blob not null,
+    -- encrypted private key
+    private_key text not null,
+    intermediate_key text
+);
+
+-- name or id of the certificate authority to automatically issue certificates with
+alter table identities add column ca text;
+alter table identities add column principals text;
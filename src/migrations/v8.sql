@@ -0,0 +1 @@
+alter table identities add column allow_rsa_sha1 integer not null default 0;
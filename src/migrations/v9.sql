@@ -0,0 +1,3 @@
+-- encrypted passphrase for passphrase-protected private keys
+alter table identities add column passphrase text;
+alter table certificate_authorities add column passphrase text;